@@ -0,0 +1,614 @@
+use std::{collections::HashMap, fmt::Display, path::Path};
+
+use crate::config::{self, Config};
+use anyhow::{format_err, Result};
+use nix::unistd::{self, AccessFlags};
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct Problem {
+    file: String,
+    line: Option<usize>,
+    message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}: line {line}: {}", self.file, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+enum Type {
+    Bool,
+    Integer,
+    Integers,
+    String,
+    Strings,
+    // table of strings with arbitrary keys
+    Map,
+    OneOf(&'static [&'static str]),
+    Table(&'static [Field]),
+    // table of tables with arbitrary keys, each having the fields
+    Tables(&'static [Field]),
+}
+
+struct Field {
+    key: &'static str,
+    ty: Type,
+    required: bool,
+}
+
+const fn required(key: &'static str, ty: Type) -> Field {
+    Field {
+        key,
+        ty,
+        required: true,
+    }
+}
+
+const fn optional(key: &'static str, ty: Type) -> Field {
+    Field {
+        key,
+        ty,
+        required: false,
+    }
+}
+
+const PROCESS: &[Field] = &[
+    required("path", Type::String),
+    optional("args", Type::Strings),
+    optional("workDir", Type::String),
+    optional("autoStart", Type::Bool),
+    optional("startSeconds", Type::Integer),
+    optional("instances", Type::Integer),
+    optional("dependsOn", Type::Strings),
+    optional("restartWithDependencies", Type::Bool),
+    optional(
+        "restartStrategy",
+        Type::OneOf(&["none", "always", "on-failure"]),
+    ),
+    optional("restartMode", Type::OneOf(&["stop-start", "overlap"])),
+    optional(
+        "stopSignal",
+        Type::OneOf(&["TERM", "HUP", "INT", "QUIT", "KILL", "USR1", "USR2"]),
+    ),
+    optional("envs", Type::Map),
+    optional("stdin", Type::Bool),
+    optional("pty", Type::Bool),
+    optional("ptyRows", Type::Integer),
+    optional("ptyCols", Type::Integer),
+    optional("listen", Type::Strings),
+    optional("startOnConnect", Type::Bool),
+    optional("schedule", Type::String),
+    optional(
+        "scheduleOverlap",
+        Type::OneOf(&["skip", "queue", "kill-previous"]),
+    ),
+    optional("preStart", Type::Strings),
+    optional("postStart", Type::Strings),
+    optional("preStop", Type::Strings),
+    optional("postStop", Type::Strings),
+    optional("hookTimeoutSeconds", Type::Integer),
+];
+
+// Whether the key is one of [program.process], which may also be given at
+// the top of the config.
+pub fn is_process_key(key: &str) -> bool {
+    PROCESS.iter().any(|f| f.key == key)
+}
+
+const PROGRAM: &[Field] = &[
+    required("process", Type::Table(PROCESS)),
+    optional(
+        "log",
+        Type::Table(&[
+            optional("path", Type::String),
+            optional("compress", Type::Bool),
+            optional("mergeCompressed", Type::Bool),
+            optional("maxDays", Type::Integer),
+            optional("maxBackups", Type::Integer),
+            optional("maxSize", Type::Integer),
+            optional("stripAnsi", Type::Bool),
+        ]),
+    ),
+    optional(
+        "events",
+        Type::Table(&[
+            optional("onStart", Type::Strings),
+            optional("onExit", Type::Strings),
+            optional("onFatal", Type::Strings),
+            optional("onRotate", Type::Strings),
+            optional("timeoutSeconds", Type::Integer),
+            optional("maxConcurrency", Type::Integer),
+        ]),
+    ),
+];
+
+const RULE: &[Field] = &[
+    optional("uids", Type::Integers),
+    optional("gids", Type::Integers),
+];
+
+const SCHEMA: &[Field] = &[
+    optional(
+        "sup",
+        Type::Table(&[
+            optional("socket", Type::String),
+            optional("socketMode", Type::String),
+            optional("socketOwner", Type::String),
+            optional("stateFile", Type::String),
+            optional("include", Type::Strings),
+            optional(
+                "access",
+                Type::Table(&[
+                    optional("uids", Type::Integers),
+                    optional("gids", Type::Integers),
+                    optional("readOnly", Type::Table(RULE)),
+                    optional("actions", Type::Tables(RULE)),
+                ]),
+            ),
+            optional(
+                "http",
+                Type::Table(&[
+                    required("listen", Type::String),
+                    required("token", Type::String),
+                    optional("dashboard", Type::Bool),
+                ]),
+            ),
+        ]),
+    ),
+    optional("program", Type::Table(PROGRAM)),
+    optional("programs", Type::Tables(PROGRAM)),
+    optional(
+        "groups",
+        Type::Tables(&[required("programs", Type::Strings)]),
+    ),
+];
+
+// Files matched by 'sup.include' may only define programs.
+const INCLUDED_SCHEMA: &[Field] = &[optional("programs", Type::Tables(PROGRAM))];
+
+// A config file which could be parsed.
+struct File {
+    path: String,
+    value: toml::Value,
+    lines: HashMap<String, usize>,
+}
+
+// Validates the config file and the files it includes against the schema,
+// then checks the paths they refer to, returning every problem found.
+pub fn check(path: &str) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let main = match check_file(path, SCHEMA, PROCESS, &mut problems) {
+        Some(main) => main,
+        None => return problems,
+    };
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    let include: Vec<String> = main
+        .value
+        .get("sup")
+        .and_then(|sup| sup.get("include"))
+        .and_then(|include| include.as_array())
+        .map(|patterns| {
+            patterns
+                .iter()
+                .filter_map(|p| p.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default();
+    let sup = config::Sup {
+        include,
+        ..Default::default()
+    };
+    let included = match sup.include_files(path) {
+        Ok(files) => files,
+        Err(e) => {
+            return vec![Problem {
+                file: String::from(path),
+                line: find_line(&main.lines, "sup.include"),
+                message: format!("{e:#}"),
+            }]
+        }
+    };
+
+    let mut files = vec![main];
+    for file in included {
+        let file = file.to_string_lossy();
+        if let Some(file) = check_file(&file, INCLUDED_SCHEMA, &[], &mut problems) {
+            files.push(file);
+        }
+    }
+    if !problems.is_empty() {
+        return problems;
+    }
+
+    let conf = match Config::load(path) {
+        Ok(conf) => conf,
+        Err(e) => {
+            return vec![Problem {
+                file: String::from(path),
+                line: None,
+                message: format!("{e:#}"),
+            }]
+        }
+    };
+    if conf.programs.is_empty() && sup.include.is_empty() {
+        problems.push(Problem {
+            file: String::from(path),
+            line: None,
+            message: String::from(
+                "no program defined, add a 'path' key, a [program] or [programs.<name>] table",
+            ),
+        });
+    }
+
+    for (name, program) in &conf.programs {
+        // programs not defined by [programs.<name>] come from [program], or
+        // from process keys at the top
+        let table = format!("programs.{name}");
+        let (file, table) = files
+            .iter()
+            .find(|f| f.value.get("programs").and_then(|p| p.get(name)).is_some())
+            .map(|f| (f, table))
+            .unwrap_or((&files[0], String::from("program")));
+        let shorthand = file.value.get(&table).is_none();
+
+        check_paths(program, &mut |key: &str, message: String| {
+            let key = match key.strip_prefix("process.") {
+                Some(key) if shorthand => String::from(key),
+                _ => format!("{table}.{key}"),
+            };
+            problems.push(Problem {
+                file: file.path.clone(),
+                line: find_line(&file.lines, &key),
+                message,
+            })
+        });
+    }
+    problems
+}
+
+// Returns an error listing every problem of the config files, if any.
+pub fn ensure_valid(path: &str) -> Result<()> {
+    let problems = check(path);
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+    Err(format_err!(
+        "invalid config file {path}:\n{}",
+        problems.join("\n")
+    ))
+}
+
+// Validates the file against the schema, returning it if it could be parsed.
+// Other keys at the top are checked together against the shorthand fields,
+// if any.
+fn check_file(
+    path: &str,
+    schema: &[Field],
+    shorthand: &[Field],
+    problems: &mut Vec<Problem>,
+) -> Option<File> {
+    let src = match std::fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(e) => {
+            problems.push(Problem {
+                file: String::from(path),
+                line: None,
+                message: format!("failed to read config file {path}: {e}"),
+            });
+            return None;
+        }
+    };
+
+    // every format is checked as TOML values, which the config maps to anyway
+    let format = config::Format::of(path);
+    let value: toml::Value = match format.parse(&src) {
+        Ok(v) => v,
+        Err(e) => {
+            problems.push(syntax_problem(path, e));
+            return None;
+        }
+    };
+
+    let lines = match format {
+        config::Format::Toml => key_lines(&src),
+        config::Format::Yaml => yaml_key_lines(&src),
+        config::Format::Json => json_key_lines(&src),
+    };
+    let mut found = Vec::new();
+    let mut report = |key: &str, message: String| {
+        found.push(Problem {
+            file: String::from(path),
+            line: find_line(&lines, key),
+            message,
+        })
+    };
+
+    match value.as_table() {
+        // without 'path' there is no shorthand, other keys are unknown
+        Some(table) if shorthand.is_empty() || !table.contains_key("path") => {
+            check_table(table, schema, "", &mut report)
+        }
+        Some(table) => {
+            let (known, other): (Vec<_>, Vec<_>) = table
+                .clone()
+                .into_iter()
+                .partition(|(key, _)| schema.iter().any(|f| f.key == key));
+            check_table(&known.into_iter().collect(), schema, "", &mut report);
+            if !other.is_empty() {
+                check_table(&other.into_iter().collect(), shorthand, "", &mut report);
+            }
+        }
+        None => report("", String::from("config is not a table")),
+    }
+    found.sort_by_key(|p| p.line);
+    problems.append(&mut found);
+
+    Some(File {
+        path: String::from(path),
+        value,
+        lines,
+    })
+}
+
+fn syntax_problem(path: &str, e: anyhow::Error) -> Problem {
+    let line = if let Some(e) = e.downcast_ref::<toml::de::Error>() {
+        e.line_col().map(|(line, _)| line + 1)
+    } else if let Some(e) = e.downcast_ref::<serde_yaml::Error>() {
+        e.location().map(|l| l.line())
+    } else {
+        e.downcast_ref::<serde_json::Error>().map(|e| e.line())
+    };
+    Problem {
+        file: String::from(path),
+        line,
+        message: e.to_string(),
+    }
+}
+
+fn check_table(
+    table: &toml::value::Table,
+    fields: &[Field],
+    prefix: &str,
+    report: &mut impl FnMut(&str, String),
+) {
+    for (key, value) in table {
+        let path = format!("{prefix}{key}");
+        match fields.iter().find(|f| f.key == key) {
+            Some(field) => check_value(value, &field.ty, &path, report),
+            None => report(&path, format!("unknown key '{path}'")),
+        }
+    }
+    for field in fields {
+        if field.required && !table.contains_key(field.key) {
+            let table_path = prefix.trim_end_matches('.');
+            report(table_path, format!("missing key '{prefix}{}'", field.key));
+        }
+    }
+}
+
+fn check_value(value: &toml::Value, ty: &Type, path: &str, report: &mut impl FnMut(&str, String)) {
+    let is_string = |v: &toml::Value| v.is_str();
+    let expected = match ty {
+        Type::Bool if !value.is_bool() => "a boolean",
+        Type::Integer if value.as_integer().is_none_or(|i| i < 0) => "a non-negative integer",
+        Type::Integers
+            if !value
+                .as_array()
+                .is_some_and(|a| a.iter().all(|i| i.as_integer().is_some_and(|i| i >= 0))) =>
+        {
+            "an array of non-negative integers"
+        }
+        Type::String if !value.is_str() => "a string",
+        Type::Strings if !value.as_array().is_some_and(|a| a.iter().all(is_string)) => {
+            "an array of strings"
+        }
+        Type::Map if !value.as_table().is_some_and(|t| t.values().all(is_string)) => {
+            "a table of strings"
+        }
+        Type::OneOf(values) => {
+            if !value.as_str().is_some_and(|v| values.contains(&v)) {
+                let values: Vec<String> = values.iter().map(|v| format!("'{v}'")).collect();
+                report(
+                    path,
+                    format!(
+                        "invalid value {value} for key '{path}', expected one of {}",
+                        values.join(", ")
+                    ),
+                );
+            }
+            return;
+        }
+        Type::Table(fields) => {
+            match value.as_table() {
+                Some(table) => check_table(table, fields, &format!("{path}."), report),
+                None => report(path, format!("key '{path}' is expected to be a table")),
+            }
+            return;
+        }
+        Type::Tables(fields) => {
+            match value.as_table() {
+                Some(tables) => {
+                    for (name, value) in tables {
+                        let path = format!("{path}.{name}");
+                        check_value(value, &Type::Table(fields), &path, report);
+                    }
+                }
+                None => report(path, format!("key '{path}' is expected to be a table")),
+            }
+            return;
+        }
+        _ => return,
+    };
+    report(
+        path,
+        format!("invalid value {value} for key '{path}', expected {expected}"),
+    );
+}
+
+// Reports problems with keys relative to the table of the program.
+fn check_paths(program: &config::Program, report: &mut impl FnMut(&str, String)) {
+    let process = &program.process;
+
+    let work_dir = if process.work_dir.is_empty() {
+        Path::new(".")
+    } else {
+        Path::new(&process.work_dir)
+    };
+    if !work_dir.is_dir() {
+        report(
+            "process.workDir",
+            format!("work directory {} does not exist", work_dir.display()),
+        );
+    }
+
+    match find_executable(&process.path) {
+        None => report(
+            "process.path",
+            format!("program {} does not exist", process.path),
+        ),
+        Some(path) => {
+            if unistd::access(&path, AccessFlags::X_OK).is_err() || path.is_dir() {
+                report(
+                    "process.path",
+                    format!("program {} is not executable", path.display()),
+                );
+            }
+        }
+    }
+
+    // the rotater creates missing directories, so check the closest existing one
+    let log_dir = Path::new(&program.log.path)
+        .parent()
+        .filter(|d| !d.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let existing = log_dir.ancestors().find(|d| d.exists());
+    if let Some(dir) = existing {
+        if unistd::access(dir, AccessFlags::W_OK).is_err() {
+            report(
+                "log.path",
+                format!("log directory {} is not writable", dir.display()),
+            );
+        }
+    }
+}
+
+// Resolves the program like spawning does, searching PATH for bare names.
+fn find_executable(program: &str) -> Option<std::path::PathBuf> {
+    if program.contains('/') {
+        let path = Path::new(program);
+        return path.exists().then(|| path.to_path_buf());
+    }
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(program))
+        .find(|p| p.exists())
+}
+
+// Maps dotted paths of tables and keys to the line numbers defining them.
+fn key_lines(src: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    let mut table = String::new();
+
+    for (i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            let header = line.split('#').next().unwrap_or_default().trim();
+            let header = header.trim_start_matches('[').trim_end_matches(']');
+            table = dotted(header);
+            lines.entry(table.clone()).or_insert(i + 1);
+        } else if let Some((key, _)) = line.split_once('=') {
+            if line.starts_with('#') {
+                continue;
+            }
+            let key = dotted(key);
+            let path = if table.is_empty() {
+                key
+            } else {
+                format!("{table}.{key}")
+            };
+            lines.entry(path).or_insert(i + 1);
+        }
+    }
+    lines
+}
+
+// Like key_lines, for YAML nesting keys by indentation.
+fn yaml_key_lines(src: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    // keys of the mappings containing the current line, with their indentation
+    let mut parents: Vec<(usize, String)> = Vec::new();
+
+    for (i, line) in src.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with('-') {
+            continue;
+        }
+        let key = match trimmed.split_once(':') {
+            Some((key, _)) => key.trim().trim_matches('"').trim_matches('\''),
+            None => continue,
+        };
+        let indent = line.len() - trimmed.len();
+        while parents.last().is_some_and(|(i, _)| *i >= indent) {
+            parents.pop();
+        }
+        let mut path: Vec<&str> = parents.iter().map(|(_, k)| k.as_str()).collect();
+        path.push(key);
+        lines.entry(path.join(".")).or_insert(i + 1);
+        parents.push((indent, String::from(key)));
+    }
+    lines
+}
+
+// Like key_lines, for JSON with one key per line, as pretty printers put it.
+fn json_key_lines(src: &str) -> HashMap<String, usize> {
+    let mut lines = HashMap::new();
+    // keys of the objects containing the current line
+    let mut parents: Vec<String> = Vec::new();
+
+    for (i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('}') {
+            parents.pop();
+            continue;
+        }
+        let key = match line.strip_prefix('"').and_then(|l| l.split_once("\":")) {
+            Some((key, _)) => key,
+            None => continue,
+        };
+        let mut path = parents.clone();
+        path.push(String::from(key));
+        lines.entry(path.join(".")).or_insert(i + 1);
+        if line.ends_with('{') {
+            parents.push(String::from(key));
+        }
+    }
+    lines
+}
+
+fn dotted(key: &str) -> String {
+    let parts: Vec<&str> = key
+        .split('.')
+        .map(|p| p.trim().trim_matches('"').trim_matches('\''))
+        .collect();
+    parts.join(".")
+}
+
+// Finds the line of the key, or of the closest table containing it.
+fn find_line(lines: &HashMap<String, usize>, key: &str) -> Option<usize> {
+    let mut key = key;
+    loop {
+        if let Some(line) = lines.get(key) {
+            return Some(*line);
+        }
+        key = &key[..key.rfind('.')?];
+    }
+}
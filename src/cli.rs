@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use clap::{ArgAction, Args, Parser, Subcommand};
+use lazy_static::lazy_static;
+
+use crate::{
+    config::{self, Action, AttachOptions, Config, LogsOptions, StatusOptions},
+    import,
+};
+
+lazy_static! {
+    static ref ARGS: Cli = Cli::parse();
+}
+
+pub fn args() -> &'static Cli {
+    &ARGS
+}
+
+// Path of the daemon socket, '--socket' or SUP_SOCKET taking precedence over
+// the config. The config is only read if given, so clients can do without it.
+pub fn socket() -> String {
+    match (&args().socket, &args().config) {
+        (Some(socket), _) => socket.clone(),
+        (None, Some(_)) => Config::get().sup.socket.clone(),
+        (None, None) => String::from(config::DEFAULT_SOCKET),
+    }
+}
+
+/// A supervisor for long-running programs.
+#[derive(Debug, Parser)]
+#[command(name = "sup", version, disable_version_flag = true)]
+pub struct Cli {
+    /// Config file, in TOML, YAML or JSON
+    #[arg(short, long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// Socket of the daemon, overriding 'sup.socket' of the config
+    #[arg(short, long, global = true, value_name = "PATH", env = "SUP_SOCKET")]
+    pub socket: Option<String>,
+
+    /// Print version
+    #[arg(short = 'v', long, action = ArgAction::Version)]
+    version: (),
+
+    /// Runs the daemon if omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the daemon in the foreground
+    Serve,
+    #[command(flatten)]
+    Request(Request),
+    /// Send a request to the daemon, needing no config file
+    Ctl {
+        #[command(subcommand)]
+        request: Request,
+    },
+    /// Validate the config file
+    Check {
+        /// Print problems as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Work with the config file
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Convert program definitions of another supervisor into a sup config
+    Import {
+        /// Format of the file
+        #[arg(long, value_enum)]
+        from: import::Source,
+        file: String,
+    },
+    /// Generate files for integrating sup with other tools
+    #[command(subcommand)]
+    Generate(GenerateCommand),
+    /// Print version
+    Version,
+}
+
+// Commands handled by the daemon.
+#[derive(Debug, Subcommand)]
+pub enum Request {
+    /// Start programs
+    Start(Targets),
+    /// Stop programs
+    Stop(Targets),
+    /// Stop and then start programs
+    Restart(Targets),
+    /// Send the reload signal to programs
+    Reload(Targets),
+    /// Kill programs
+    Kill(Targets),
+    /// Show status of programs
+    Status {
+        /// Print status as JSON
+        #[arg(long)]
+        json: bool,
+        /// Programs to show, all if none is given
+        programs: Vec<String>,
+        #[command(flatten)]
+        request: RequestArgs,
+    },
+    /// Show recent output of a program
+    Logs {
+        /// Number of lines to show
+        #[arg(short = 'n', default_value_t = 10)]
+        lines: usize,
+        /// Keep streaming new output
+        #[arg(short, long)]
+        follow: bool,
+        /// Only show stderr, as kept in memory: at most the last 1000 lines since sup started
+        #[arg(long)]
+        stderr: bool,
+        /// May be omitted if there is only one program
+        program: Option<String>,
+    },
+    /// Stream output of a program, optionally forwarding stdin to it
+    Attach {
+        /// Forward stdin to the program
+        #[arg(long)]
+        stdin: bool,
+        /// Key sequence to detach, like 'ctrl-a,d'
+        #[arg(long, default_value = "ctrl-p,ctrl-q", value_parser = parse_keys)]
+        detach_keys: Keys,
+        /// May be omitted if there is only one program
+        program: Option<String>,
+    },
+    /// Stop programs and the daemon
+    Exit(RequestArgs),
+    /// Like exit, and wait for the daemon to go away
+    ExitWait(RequestArgs),
+    /// Show changes of the config file not applied yet
+    Reread(RequestArgs),
+    /// Apply changes of the config file
+    Update(RequestArgs),
+    /// Re-execute the sup binary, e.g. after installing a new version,
+    /// keeping programs running
+    Upgrade(RequestArgs),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ConfigCommand {
+    /// Print the config with includes merged and defaults filled in
+    Dump {
+        /// Output format, that of the config file by default
+        #[arg(long, value_enum)]
+        format: Option<config::Format>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GenerateCommand {
+    /// Print a systemd unit running sup with the config
+    Systemd,
+    /// Print shell completions
+    Completions { shell: clap_complete::Shell },
+}
+
+#[derive(Debug, Args)]
+pub struct Targets {
+    /// Programs to act on, all if none is given. 'all' names every program, and a group name
+    /// or '<group>:*' the programs of the group
+    pub programs: Vec<String>,
+    #[command(flatten)]
+    pub request: RequestArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct RequestArgs {
+    /// Seconds to wait for the daemon to respond
+    #[arg(long, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+}
+
+impl RequestArgs {
+    fn timeout(&self) -> Option<Duration> {
+        self.timeout.map(Duration::from_secs)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Keys(Vec<u8>);
+
+fn parse_keys(keys: &str) -> Result<Keys, String> {
+    config::parse_keys(keys)
+        .map(Keys)
+        .map_err(|e| e.to_string())
+}
+
+impl Request {
+    // The action for the daemon and how long to wait for its response.
+    pub fn action(&self) -> (Action, Option<Duration>) {
+        let with = |action, request: &RequestArgs| (action, request.timeout());
+        match self {
+            Request::Start(t) => with(Action::Start(t.programs.clone()), &t.request),
+            Request::Stop(t) => with(Action::Stop(t.programs.clone()), &t.request),
+            Request::Restart(t) => with(Action::Restart(t.programs.clone()), &t.request),
+            Request::Reload(t) => with(Action::Reload(t.programs.clone()), &t.request),
+            Request::Kill(t) => with(Action::Kill(t.programs.clone()), &t.request),
+            Request::Status {
+                json,
+                programs,
+                request,
+            } => with(
+                Action::Status(StatusOptions {
+                    programs: programs.clone(),
+                    json: *json,
+                }),
+                request,
+            ),
+            Request::Logs {
+                lines,
+                follow,
+                stderr,
+                program,
+            } => (
+                Action::Logs(LogsOptions {
+                    lines: *lines,
+                    follow: *follow,
+                    stderr: *stderr,
+                    program: program.clone(),
+                }),
+                None,
+            ),
+            Request::Attach {
+                stdin,
+                detach_keys,
+                program,
+            } => (
+                Action::Attach(AttachOptions {
+                    stdin: *stdin,
+                    detach_keys: detach_keys.0.clone(),
+                    program: program.clone(),
+                }),
+                None,
+            ),
+            Request::Exit(request) => with(Action::Exit, request),
+            Request::ExitWait(request) => with(Action::ExitWait, request),
+            Request::Reread(request) => with(Action::Reread, request),
+            Request::Update(request) => with(Action::Update, request),
+            Request::Upgrade(request) => with(Action::Upgrade, request),
+        }
+    }
+}
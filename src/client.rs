@@ -1,37 +1,222 @@
 use std::{
-    io::{Read, Write},
-    os::unix::net::UnixStream,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::Shutdown,
+    os::unix::{io::AsRawFd, net::UnixStream},
     path::Path,
+    time::{Duration, Instant},
 };
 
-use crate::config::{Action, Config};
+use crate::{
+    cli,
+    config::{Action, AttachOptions, ProgramResult},
+};
 use anyhow::{format_err, Context, Result};
+use nix::sys::termios;
 
-pub fn request(action: Action) -> Result<String> {
-    if matches!(action, Action::Serve) {
-        Err(format_err!(
-            "client does not support the action {}",
-            Action::Serve
-        ))?;
-    }
-    let socket = &Config::get().sup.socket;
-
-    let mut conn =
-        UnixStream::connect(Path::new(socket)).context("failed to connect to sup socket")?;
+// Sends the action to the daemon and prints its response, waiting for it no
+// longer than the timeout if one is given.
+pub fn request(action: Action, timeout: Option<Duration>) -> Result<()> {
+    let socket = cli::socket();
+    let deadline = timeout.map(|t| Instant::now() + t);
 
-    let action = action.to_string();
+    let mut conn = UnixStream::connect(Path::new(&socket))
+        .context(format!("failed to connect to sup socket {socket}"))?;
+    conn.set_read_timeout(timeout)
+        .context("failed to set timeout on sup socket")?;
 
-    conn.write_all(action.as_bytes())
+    conn.write_all(action.to_string().as_bytes())
         .context("failed to send action")?;
 
+    match action {
+        Action::Logs(_) => return receive_logs(conn),
+        Action::Attach(opts) => return attach(conn, opts),
+        _ => {}
+    }
+
     let mut rsp = String::new();
-    conn.read_to_string(&mut rsp)
+    conn.read_to_string(&mut rsp).map_err(|e| match e.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            format_err!("timed out waiting for sup to respond")
+        }
+        _ => anyhow::Error::new(e).context("failed to receive response from sup server"),
+    })?;
+
+    if let Some(results) = rsp.strip_prefix("RESULTS\n") {
+        let results: Vec<ProgramResult> =
+            serde_json::from_str(results).context("failed to parse results from sup server")?;
+        if !print_results(&results) {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // replies with a body put it after an 'OK' line
+    match rsp.strip_prefix("OK") {
+        Some("") => {}
+        Some(body) if body.starts_with('\n') => print!("{}", &body[1..]),
+        _ => {
+            println!("{rsp}");
+            std::process::exit(1);
+        }
+    }
+
+    if matches!(action, Action::ExitWait) {
+        // the daemon exits right after responding, and then refuses connections
+        while UnixStream::connect(Path::new(&socket)).is_ok() {
+            if deadline.is_some_and(|d| Instant::now() >= d) {
+                return Err(format_err!("timed out waiting for sup to exit"));
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    Ok(())
+}
+
+// Prints the outcome of an action for each program, or only the error if
+// there is just one, returning whether all succeeded.
+fn print_results(results: &[ProgramResult]) -> bool {
+    if let [result] = results {
+        if let Some(e) = &result.error {
+            println!("{e}");
+        }
+        return result.error.is_none();
+    }
+    let width = results.iter().map(|r| r.program.len()).max().unwrap_or(0);
+    for result in results {
+        match &result.error {
+            Some(e) => println!("{:width$}  failed: {e}", result.program),
+            None => println!("{:width$}  ok", result.program),
+        }
+    }
+    results.iter().all(|r| r.error.is_none())
+}
+
+// Streamed responses start with an 'OK' line, otherwise the response is an
+// error message.
+fn receive_ok(conn: UnixStream) -> Result<BufReader<UnixStream>> {
+    let mut conn = BufReader::new(conn);
+    let mut head = Vec::new();
+    conn.read_until(b'\n', &mut head)
         .context("failed to receive response from sup server")?;
 
-    if rsp != "OK" {
+    if head != b"OK\n" {
+        let mut rsp = String::from_utf8_lossy(&head).into_owned();
+        conn.read_to_string(&mut rsp)
+            .context("failed to receive response from sup server")?;
         println!("{rsp}");
         std::process::exit(1);
     }
+    Ok(conn)
+}
+
+fn receive_logs(conn: UnixStream) -> Result<()> {
+    let conn = receive_ok(conn)?;
+    copy_output(conn)
+}
+
+fn attach(conn: UnixStream, opts: AttachOptions) -> Result<()> {
+    let conn = receive_ok(conn)?;
+
+    if opts.stdin {
+        let input = conn
+            .get_ref()
+            .try_clone()
+            .context("failed to clone sup connection")?;
+        let saved = enter_raw_mode()?;
+
+        std::thread::spawn(move || {
+            let detached = forward_stdin(input, &opts.detach_keys);
+            if let Some(saved) = saved {
+                let _ = termios::tcsetattr(0, termios::SetArg::TCSANOW, &saved);
+            }
+            match detached {
+                Ok(()) => {
+                    println!("\ndetached");
+                    std::process::exit(0);
+                }
+                Err(e) => {
+                    println!("\nfailed to forward stdin: {e:#}");
+                    std::process::exit(1);
+                }
+            }
+        });
+    }
+
+    copy_output(conn)
+}
 
-    Ok(rsp)
+// Copies output from the server to stdout until the server closes the
+// connection, flushing as output comes, as prompts usually do not end with a
+// newline.
+fn copy_output(mut conn: BufReader<UnixStream>) -> Result<()> {
+    let mut stdout = std::io::stdout();
+    let mut buf = [0; 4096];
+    loop {
+        let len = conn
+            .read(&mut buf)
+            .context("failed to receive output from sup server")?;
+        if len == 0 {
+            break;
+        }
+        stdout.write_all(&buf[..len])?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+// Puts the terminal into raw mode, so every key gets forwarded as typed,
+// returning the previous settings. Does nothing if stdin is not a terminal.
+fn enter_raw_mode() -> Result<Option<termios::Termios>> {
+    let fd = std::io::stdin().as_raw_fd();
+    if !nix::unistd::isatty(fd).unwrap_or(false) {
+        return Ok(None);
+    }
+    let saved = termios::tcgetattr(fd).context("failed to get terminal attributes")?;
+    let mut raw = saved.clone();
+    termios::cfmakeraw(&mut raw);
+    // keep translating '\n' into '\r\n' for the output
+    raw.output_flags |= termios::OutputFlags::OPOST | termios::OutputFlags::ONLCR;
+    termios::tcsetattr(fd, termios::SetArg::TCSANOW, &raw)
+        .context("failed to set terminal into raw mode")?;
+    Ok(Some(saved))
+}
+
+// Forwards stdin to the server until the detach key sequence is typed. Keys
+// matching a prefix of the sequence are held back until the sequence breaks.
+fn forward_stdin(mut conn: UnixStream, detach_keys: &[u8]) -> Result<()> {
+    let mut stdin = std::io::stdin().lock();
+    let mut buf = [0; 1024];
+    let mut matched = 0;
+
+    loop {
+        let len = stdin.read(&mut buf).context("failed to read stdin")?;
+        if len == 0 {
+            // nothing more to forward, keep receiving output
+            conn.shutdown(Shutdown::Write)?;
+            loop {
+                std::thread::park();
+            }
+        }
+
+        let mut out = Vec::with_capacity(len);
+        for &b in &buf[..len] {
+            if b == detach_keys[matched] {
+                matched += 1;
+                if matched == detach_keys.len() {
+                    conn.write_all(&out).context("failed to send input")?;
+                    return Ok(());
+                }
+                continue;
+            }
+            out.extend_from_slice(&detach_keys[..matched]);
+            matched = 0;
+            if b == detach_keys[0] {
+                matched = 1;
+            } else {
+                out.push(b);
+            }
+        }
+        conn.write_all(&out).context("failed to send input")?;
+    }
 }
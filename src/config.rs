@@ -1,92 +1,823 @@
-use std::{collections::HashMap, fmt::Display, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsStr,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
 use lazy_static::lazy_static;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use anyhow::{Context, Result};
+use crate::{check, cli, listen, schedule};
+use anyhow::{format_err, Context, Result};
 
 // TODO：lazy_static 怎么实现的
 lazy_static! {
-    static ref CONFIG: Arc<Config> = Arc::new(Config::from_args().unwrap());
+    static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::from_args().unwrap()));
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Config {
+    #[serde(default)]
     pub sup: Sup,
-    pub program: Program,
+    // The single program of older configs, moved into 'programs' after loading
+    // under the basename of its executable.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub program: Option<Program>,
+    #[serde(default)]
+    pub programs: BTreeMap<String, Program>,
+    // Programs acted on together by the name of the group.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Group>,
+    // Other keys at the top, taken as keys of [program.process] if 'path' is
+    // among them, as a shorthand for a single program like
+    // 'path = "/usr/bin/foo"'. Made the [program] after loading.
+    #[serde(flatten, skip_serializing)]
+    process: serde_json::Map<String, serde_json::Value>,
+}
+
+// Names every program at once in actions, unless a program has this name.
+pub const ALL: &str = "all";
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Group {
+    pub programs: Vec<String>,
+}
+
+// Files matched by 'sup.include' may only define programs.
+#[derive(Deserialize)]
+struct Included {
+    #[serde(default)]
+    programs: BTreeMap<String, Program>,
 }
 
 impl Config {
     fn from_args() -> Result<Self> {
-        let args: Vec<String> = std::env::args().collect();
-        check_args(&args);
-
-        let conf_path = &args[2];
-        let conf = std::fs::read_to_string(conf_path)
-            .context(format!("failed to read config file {}", conf_path))?;
-        let conf: Config = toml::from_str(&conf)
-            .context(format!("failed to deserialize config file {}", conf_path))?;
+        Config::load(&Config::path())
+    }
+
+    // Reads the config file and the files it includes, merging every program
+    // defined into 'programs' and filling in defaults depending on other fields.
+    pub fn load(path: &str) -> Result<Self> {
+        let src = std::fs::read_to_string(path)
+            .context(format!("failed to read config file {}", path))?;
+        let mut conf: Config = Format::of(path)
+            .parse(&src)
+            .context(format!("failed to deserialize config file {}", path))?;
+
+        // where each program got defined, to report duplicates
+        let mut defined_in: HashMap<String, String> = HashMap::new();
+        for name in conf.programs.keys() {
+            defined_in.insert(name.clone(), String::from(path));
+        }
+        if !conf.process.is_empty() {
+            let shorthand = conf.process.contains_key("path");
+            let unknown: Vec<String> = conf
+                .process
+                .keys()
+                .filter(|key| !shorthand || !check::is_process_key(key))
+                .map(|key| format!("'{key}'"))
+                .collect();
+            if !unknown.is_empty() {
+                return Err(format_err!(
+                    "unknown key {} at the top of {path}",
+                    unknown.join(", ")
+                ));
+            }
+            if conf.program.is_some() {
+                return Err(format_err!(
+                    "the program is defined twice in {path}, by [program] and keys at the top"
+                ));
+            }
+            let process = serde_json::Value::Object(std::mem::take(&mut conf.process));
+            conf.program = Some(Program {
+                process: serde_json::from_value(process)
+                    .context(format!("failed to deserialize the program of {path}"))?,
+                ..Default::default()
+            });
+        }
+        if let Some(program) = conf.program.take() {
+            let name = program.default_name();
+            if conf.programs.contains_key(&name) {
+                return Err(format_err!(
+                    "program '{name}' is defined twice in {path}, by [program] and [programs.{name}]"
+                ));
+            }
+            defined_in.insert(name.clone(), String::from(path));
+            conf.programs.insert(name, program);
+        }
+
+        conf.sup.socket_mode()?;
+        if let Some(http) = &conf.sup.http {
+            if http.token.is_empty() {
+                return Err(format_err!(
+                    "empty sup.http.token in {path}, the HTTP API requires a token"
+                ));
+            }
+        }
+        for action in conf.sup.access.actions.keys() {
+            if !Action::NAMES.contains(&action.as_str()) {
+                return Err(format_err!(
+                    "unknown action '{action}' in sup.access.actions of {path}"
+                ));
+            }
+        }
+
+        for file in conf.sup.include_files(path)? {
+            let file = file.to_string_lossy().into_owned();
+            let src = std::fs::read_to_string(&file)
+                .context(format!("failed to read included config file {}", file))?;
+            let included: Included = Format::of(&file).parse(&src).context(format!(
+                "failed to deserialize included config file {}",
+                file
+            ))?;
+            for (name, program) in included.programs {
+                if let Some(other) = defined_in.get(&name) {
+                    return Err(format_err!(
+                        "program '{name}' is defined in both {other} and {file}"
+                    ));
+                }
+                defined_in.insert(name.clone(), file.clone());
+                conf.programs.insert(name, program);
+            }
+        }
+
+        for (name, program) in conf.programs.iter_mut() {
+            if !is_valid_name(name) {
+                return Err(format_err!(
+                    "invalid program name '{name}' in {}, expected letters, digits, '-', '_' or '.'",
+                    defined_in[name]
+                ));
+            }
+            if program.process.path.is_empty() {
+                return Err(format_err!(
+                    "empty process.path of program '{name}' in {}",
+                    defined_in[name]
+                ));
+            }
+            if program.log.merge_compressed {
+                return Err(format_err!(
+                    "log.mergeCompressed of program '{name}' in {} is not supported",
+                    defined_in[name]
+                ));
+            }
+            if program.log.path.is_empty() {
+                program.log.path = match program.process.instances {
+                    1 => format!("{name}.log"),
+                    _ => format!("{name}.{{instance}}.log"),
+                };
+            }
+            program.log.strip_ansi.get_or_insert(program.process.pty);
+            program
+                .check_instances(name)
+                .context(format!("invalid program '{name}' in {}", defined_in[name]))?;
+        }
+        conf.dependency_order()?;
+
+        for (name, group) in &conf.groups {
+            if !is_valid_name(name) || name == ALL {
+                return Err(format_err!(
+                    "invalid group name '{name}' in {path}, expected letters, digits, '-', '_' or '.' other than '{ALL}'"
+                ));
+            }
+            if conf.programs.contains_key(name) {
+                return Err(format_err!(
+                    "group '{name}' in {path} has the name of a program"
+                ));
+            }
+            for program in &group.programs {
+                if !conf.programs.contains_key(program) {
+                    return Err(format_err!(
+                        "group '{name}' in {path} names unknown program '{program}'"
+                    ));
+                }
+            }
+        }
         Ok(conf)
     }
+
+    // Serializes the config as a single file, with every program defined
+    // in it rather than included.
+    pub fn dump(&self, format: Format) -> Result<String> {
+        let mut conf = self.clone();
+        conf.sup.include.clear();
+        format.serialize(&conf)
+    }
+
+    // Path to the config file given in args.
+    pub fn path() -> String {
+        match &cli::args().config {
+            Some(path) => path.clone(),
+            None => {
+                eprintln!("error: no config file given, pass it with '--config <PATH>'");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    // Names of the programs, each after the programs it depends on.
+    pub fn dependency_order(&self) -> Result<Vec<String>> {
+        fn visit<'a>(
+            conf: &'a Config,
+            name: &'a str,
+            path: &mut Vec<&'a str>,
+            order: &mut Vec<String>,
+        ) -> Result<()> {
+            if order.iter().any(|n| n == name) {
+                return Ok(());
+            }
+            if let Some(start) = path.iter().position(|n| *n == name) {
+                let mut cycle = path[start..].to_vec();
+                cycle.push(name);
+                return Err(format_err!("dependency cycle: {}", cycle.join(" -> ")));
+            }
+            path.push(name);
+            for dependency in &conf.programs[name].process.depends_on {
+                if !conf.programs.contains_key(dependency) {
+                    return Err(format_err!(
+                        "program '{name}' depends on unknown program '{dependency}'"
+                    ));
+                }
+                visit(conf, dependency, path, order)?;
+            }
+            path.pop();
+            order.push(String::from(name));
+            Ok(())
+        }
+
+        let mut order = Vec::new();
+        for name in self.programs.keys() {
+            visit(self, name, &mut Vec::new(), &mut order)?;
+        }
+        Ok(order)
+    }
+
+    // The programs the program depends on, directly or through others.
+    pub fn dependencies(&self, program: &str) -> BTreeSet<String> {
+        let mut found = BTreeSet::new();
+        let mut next = vec![program];
+        while let Some(name) = next.pop() {
+            for dependency in self
+                .programs
+                .get(name)
+                .iter()
+                .flat_map(|p| &p.process.depends_on)
+            {
+                if found.insert(dependency.clone()) {
+                    next.push(dependency);
+                }
+            }
+        }
+        found
+    }
+
+    // The programs depending on the program, directly or through others.
+    pub fn dependents(&self, program: &str) -> BTreeSet<String> {
+        let mut found = BTreeSet::new();
+        let mut next = vec![String::from(program)];
+        while let Some(name) = next.pop() {
+            for (dependent, p) in &self.programs {
+                if p.process.depends_on.contains(&name) && found.insert(dependent.clone()) {
+                    next.push(dependent.clone());
+                }
+            }
+        }
+        found
+    }
+
+    // Every instance of every program by name, see Program::instances.
+    pub fn instances(&self) -> BTreeMap<String, Program> {
+        self.programs
+            .iter()
+            .flat_map(|(name, program)| program.instances(name))
+            .collect()
+    }
+
+    pub fn instance(&self, name: &str) -> Option<Program> {
+        let program = program_name(name);
+        self.programs
+            .get(program)?
+            .instances(program)
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, instance)| instance)
+    }
+
     pub fn get() -> Arc<Config> {
-        Arc::clone(&CONFIG)
+        Arc::clone(&CONFIG.read().unwrap())
+    }
+
+    // Parses the config files again without applying them.
+    pub fn reread() -> Result<Config> {
+        Config::from_args()
+    }
+
+    // Parses the config files again and makes them the current config,
+    // returning the previous one.
+    pub fn reload() -> Result<Arc<Config>> {
+        let conf = Arc::new(Config::from_args()?);
+        let mut current = CONFIG.write().unwrap();
+        Ok(std::mem::replace(&mut current, conf))
+    }
+
+    // Returns names of the sections differing from the other config.
+    pub fn diff(&self, other: &Config) -> Vec<String> {
+        let mut changed = Vec::new();
+        if self.sup != other.sup {
+            changed.push(String::from("sup"));
+        }
+        for (name, program) in &self.programs {
+            let other = match other.programs.get(name) {
+                Some(other) => other,
+                None => {
+                    changed.push(format!("programs.{name} (removed)"));
+                    continue;
+                }
+            };
+            if program.process != other.process {
+                changed.push(format!("programs.{name}.process"));
+            }
+            if program.log != other.log {
+                changed.push(format!("programs.{name}.log"));
+            }
+            if program.events != other.events {
+                changed.push(format!("programs.{name}.events"));
+            }
+        }
+        for name in other.programs.keys() {
+            if !self.programs.contains_key(name) {
+                changed.push(format!("programs.{name} (added)"));
+            }
+        }
+        if self.groups != other.groups {
+            changed.push(String::from("groups"));
+        }
+        changed
     }
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+// Format of a config file, told by its extension. Files without a known
+// extension are TOML.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    pub fn of(path: &str) -> Format {
+        match Path::new(path).extension().and_then(OsStr::to_str) {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(self, src: &str) -> Result<T> {
+        Ok(match self {
+            Format::Toml => toml::from_str(src)?,
+            Format::Yaml => serde_yaml::from_str(src)?,
+            Format::Json => serde_json::from_str(src)?,
+        })
+    }
+
+    pub fn serialize<T: Serialize>(self, value: &T) -> Result<String> {
+        Ok(match self {
+            // going through a value puts tables after plain keys, as TOML requires
+            Format::Toml => toml::to_string(&toml::Value::try_from(value)?)?,
+            Format::Yaml => serde_yaml::to_string(value)?,
+            Format::Json => serde_json::to_string_pretty(value)? + "\n",
+        })
+    }
+}
+
+// Replaces '{instance}' with the instance number, and '{instance+N}' with the
+// number plus N, e.g. for ports.
+fn with_instance(s: &str, instance: u32) -> String {
+    const START: &str = "{instance";
+    let mut templated = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find(START) {
+        templated.push_str(&rest[..start]);
+        let after = &rest[start + START.len()..];
+        let value = after.find('}').and_then(|end| {
+            let offset = match &after[..end] {
+                "" => 0,
+                offset => offset.strip_prefix('+')?.parse::<u64>().ok()?,
+            };
+            Some((offset + u64::from(instance), end))
+        });
+        match value {
+            Some((value, end)) => {
+                templated.push_str(&value.to_string());
+                rest = &after[end + 1..];
+            }
+            None => {
+                templated.push_str(START);
+                rest = after;
+            }
+        }
+    }
+    templated.push_str(rest);
+    templated
+}
+
+// The name of the program an instance belongs to, see Program::instances.
+pub fn program_name(instance: &str) -> &str {
+    instance
+        .split_once(':')
+        .map_or(instance, |(program, _)| program)
+}
+
+// Program names end up in actions, which are split on whitespace.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+// Where the daemon listens unless told otherwise.
+pub const DEFAULT_SOCKET: &str = "/tmp/sup.sock";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct Sup {
     pub socket: String,
+    // Permission bits of the socket in octal, like '0660'.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket_mode: Option<String>,
+    // 'user' or 'user:group' to own the socket, by name or id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub socket_owner: Option<String>,
+    // Where the state of programs is saved, next to the socket by default.
+    pub state_file: String,
+    pub include: Vec<String>,
+    pub access: Access,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http: Option<Http>,
+}
+
+// HTTP API for remote control, off unless configured.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Http {
+    // Address to listen on, like '127.0.0.1:9001'.
+    pub listen: String,
+    // Clients authenticate with header 'Authorization: Bearer <token>'.
+    pub token: String,
+    // Serves a web page for managing the programs at '/'.
+    #[serde(default)]
+    pub dashboard: bool,
+}
+
+impl Default for Sup {
+    fn default() -> Self {
+        Sup {
+            socket: String::from(DEFAULT_SOCKET),
+            socket_mode: None,
+            socket_owner: None,
+            state_file: String::new(),
+            include: Vec::new(),
+            access: Access::default(),
+            http: None,
+        }
+    }
+}
+
+impl Sup {
+    pub fn state_file(&self) -> PathBuf {
+        if self.state_file.is_empty() {
+            Path::new(&self.socket).with_extension("state")
+        } else {
+            PathBuf::from(&self.state_file)
+        }
+    }
+
+    pub fn socket_mode(&self) -> Result<Option<u32>> {
+        match &self.socket_mode {
+            None => Ok(None),
+            Some(mode) => match u32::from_str_radix(mode, 8) {
+                Ok(bits) if bits <= 0o7777 => Ok(Some(bits)),
+                _ => Err(format_err!(
+                    "invalid socket mode '{mode}', expected octal permission bits like '0660'"
+                )),
+            },
+        }
+    }
+
+    // Expands the include patterns, relative ones against the directory of
+    // the config file, into the matching files in alphabetical order.
+    pub fn include_files(&self, conf_path: &str) -> Result<Vec<PathBuf>> {
+        let dir = Path::new(conf_path).parent().unwrap_or(Path::new(""));
+        let mut files = Vec::new();
+        for pattern in &self.include {
+            let pattern = dir.join(pattern);
+            let pattern = pattern.to_string_lossy();
+            let paths =
+                glob::glob(&pattern).context(format!("invalid include pattern '{pattern}'"))?;
+            for path in paths {
+                files.push(path.context(format!("failed to expand include pattern '{pattern}'"))?);
+            }
+        }
+        Ok(files)
+    }
+}
+
+// Who may send which actions over the socket, told by the credentials of
+// the connecting process. Root and the user running sup may send any.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Access {
+    // Allowed to send any action.
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+    // Also allowed to send read-only actions.
+    pub read_only: Rule,
+    // Rules for single actions by name, replacing the ones above for them.
+    pub actions: BTreeMap<String, Rule>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rule {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+}
+
+impl Rule {
+    fn allows(&self, uid: u32, gids: &[u32]) -> bool {
+        self.uids.contains(&uid) || gids.iter().any(|gid| self.gids.contains(gid))
+    }
+}
+
+impl Access {
+    // Whether a process with the user and groups may send the action.
+    pub fn allows(&self, action: &Action, uid: u32, gids: &[u32]) -> bool {
+        if uid == 0 || uid == nix::unistd::getuid().as_raw() {
+            return true;
+        }
+        if let Some(rule) = self.actions.get(action.name()) {
+            return rule.allows(uid, gids);
+        }
+        let all = Rule {
+            uids: self.uids.clone(),
+            gids: self.gids.clone(),
+        };
+        all.allows(uid, gids) || (action.is_read_only() && self.read_only.allows(uid, gids))
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Program {
     pub process: Process,
+    #[serde(default)]
     pub log: Log,
+    #[serde(default)]
+    pub events: Events,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+impl Program {
+    // The copies of the program to run, named '<name>:<instance>' if more
+    // than one, each with its instance number in SUP_INSTANCE and in place
+    // of the templates in args, envs, listen and the log path.
+    pub fn instances(&self, name: &str) -> Vec<(String, Program)> {
+        let count = self.process.instances;
+        (0..count)
+            .map(|i| {
+                let mut program = self.clone();
+                let process = &mut program.process;
+                let template = |values: &mut Vec<String>| {
+                    values.iter_mut().for_each(|v| *v = with_instance(v, i));
+                };
+                template(&mut process.args);
+                template(&mut process.listen);
+                process
+                    .envs
+                    .values_mut()
+                    .for_each(|v| *v = with_instance(v, i));
+                process
+                    .envs
+                    .insert(String::from("SUP_INSTANCE"), i.to_string());
+                program.log.path = with_instance(&program.log.path, i);
+
+                match count {
+                    1 => (String::from(name), program),
+                    _ => (format!("{name}:{i}"), program),
+                }
+            })
+            .collect()
+    }
+
+    fn check_instances(&self, name: &str) -> Result<()> {
+        if self.process.instances == 0 {
+            return Err(format_err!("'instances' must be at least 1"));
+        }
+        let mut log_paths = HashMap::new();
+        self.process.check_schedule()?;
+        for (instance, program) in self.instances(name) {
+            program.process.check_listen()?;
+            if let Some(other) = log_paths.insert(program.log.path.clone(), instance.clone()) {
+                return Err(format_err!(
+                    "instances {other} and {instance} log to the same file, put '{{instance}}' in 'log.path'"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Name of a program defined by [program], the basename of its executable.
+    fn default_name(&self) -> String {
+        let path = Path::new(&self.process.path);
+        let name = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or("program");
+        String::from(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct Process {
     pub path: String,
     pub args: Vec<String>,
     pub work_dir: String,
     pub auto_start: bool,
     pub start_seconds: u64,
+    // Copies of the process to run, see Program::instances.
+    pub instances: u32,
+    // Programs started before and stopped after this one.
+    pub depends_on: Vec<String>,
+    // Restarts the process whenever a program it depends on starts anew.
+    pub restart_with_dependencies: bool,
     pub restart_strategy: RestartStrategy,
+    pub restart_mode: RestartMode,
+    pub stop_signal: StopSignal,
     pub envs: HashMap<String, String>,
+    pub stdin: bool,
+    pub pty: bool,
+    pub pty_rows: u16,
+    pub pty_cols: u16,
+    // Addresses sup listens on, passing the sockets to the child by the
+    // socket activation protocol.
+    pub listen: Vec<String>,
+    // Starts the process on a connection to the sockets while not running.
+    pub start_on_connect: bool,
+    // Cron schedule to launch the process on instead of keeping it running,
+    // see schedule::Schedule.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    pub schedule_overlap: ScheduleOverlap,
+    pub pre_start: Vec<String>,
+    pub post_start: Vec<String>,
+    pub pre_stop: Vec<String>,
+    pub post_stop: Vec<String>,
+    pub hook_timeout_seconds: u64,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+impl Process {
+    fn check_listen(&self) -> Result<()> {
+        for addr in &self.listen {
+            addr.parse::<listen::Address>()?;
+        }
+        if self.listen.len() > listen::MAX_SOCKETS {
+            return Err(format_err!(
+                "at most {} addresses may be given in 'listen'",
+                listen::MAX_SOCKETS
+            ));
+        }
+        if self.start_on_connect && self.listen.is_empty() {
+            return Err(format_err!(
+                "'startOnConnect' requires addresses in 'listen'"
+            ));
+        }
+        Ok(())
+    }
+
+    fn check_schedule(&self) -> Result<()> {
+        let schedule = match &self.schedule {
+            Some(schedule) => schedule.parse::<schedule::Schedule>()?,
+            None => return Ok(()),
+        };
+        if schedule.next_after(chrono::Local::now()).is_none() {
+            return Err(format_err!(
+                "schedule '{}' never comes up",
+                self.schedule.as_deref().unwrap_or_default()
+            ));
+        }
+        if self.start_on_connect {
+            return Err(format_err!(
+                "'startOnConnect' can not be combined with 'schedule'"
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Process {
+    fn default() -> Self {
+        Process {
+            path: String::new(),
+            args: Vec::new(),
+            work_dir: String::new(),
+            auto_start: false,
+            start_seconds: 1,
+            instances: 1,
+            depends_on: Vec::new(),
+            restart_with_dependencies: false,
+            restart_strategy: RestartStrategy::default(),
+            restart_mode: RestartMode::default(),
+            stop_signal: StopSignal::default(),
+            envs: HashMap::new(),
+            stdin: false,
+            pty: false,
+            pty_rows: 24,
+            pty_cols: 80,
+            listen: Vec::new(),
+            start_on_connect: false,
+            schedule: None,
+            schedule_overlap: ScheduleOverlap::default(),
+            pre_start: Vec::new(),
+            post_start: Vec::new(),
+            pre_stop: Vec::new(),
+            post_stop: Vec::new(),
+            hook_timeout_seconds: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
 pub struct Log {
+    // Empty means '<program name>.log', filled in after loading.
     pub path: String,
     pub compress: bool,
     pub merge_compressed: bool,
     pub max_days: u32,
     pub max_backups: u32,
     pub max_size: u64,
+    // None means same as 'pty', filled in after loading.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_ansi: Option<bool>,
+}
+
+impl Default for Log {
+    fn default() -> Self {
+        Log {
+            path: String::new(),
+            compress: false,
+            merge_compressed: false,
+            max_days: 0,
+            max_backups: 0,
+            max_size: 128,
+            strip_ansi: None,
+        }
+    }
 }
 
-// TODO: PartialEq、Clone derive 啥意思？？？
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Events {
+    pub on_start: Vec<String>,
+    pub on_exit: Vec<String>,
+    pub on_fatal: Vec<String>,
+    pub on_rotate: Vec<String>,
+    pub timeout_seconds: u64,
+    pub max_concurrency: usize,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            on_start: Vec::new(),
+            on_exit: Vec::new(),
+            on_fatal: Vec::new(),
+            on_rotate: Vec::new(),
+            timeout_seconds: 30,
+            max_concurrency: 4,
+        }
+    }
+}
+
+// TODO: PartialEq、Clone derive 啥意思？？？
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum RestartStrategy {
     #[serde(rename = "none")]
     None,
     #[serde(rename = "always")]
     Always,
+    #[default]
     #[serde(rename = "on-failure")]
     OnFailure,
 }
 
-impl Default for RestartStrategy {
-    fn default() -> Self {
-        RestartStrategy::Always
-    }
-}
-
 impl Display for RestartStrategy {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -97,101 +828,374 @@ impl Display for RestartStrategy {
     }
 }
 
+// How the restart action replaces the process.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RestartMode {
+    // stops the process, then starts a new one
+    #[default]
+    #[serde(rename = "stop-start")]
+    StopStart,
+    // starts a new process, and stops the old one once the new one is ready
+    #[serde(rename = "overlap")]
+    Overlap,
+}
+
+// What to do when a scheduled program comes up while it is still running.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ScheduleOverlap {
+    // lets the running process be
+    #[default]
+    #[serde(rename = "skip")]
+    Skip,
+    // launches the process again once the running one exited
+    #[serde(rename = "queue")]
+    Queue,
+    // stops the running process, then launches a new one
+    #[serde(rename = "kill-previous")]
+    KillPrevious,
+}
+
+// Signal sent to the process on stop.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StopSignal {
+    #[default]
+    #[serde(rename = "TERM")]
+    Term,
+    #[serde(rename = "HUP")]
+    Hup,
+    #[serde(rename = "INT")]
+    Int,
+    #[serde(rename = "QUIT")]
+    Quit,
+    #[serde(rename = "KILL")]
+    Kill,
+    #[serde(rename = "USR1")]
+    Usr1,
+    #[serde(rename = "USR2")]
+    Usr2,
+}
+
+impl StopSignal {
+    pub fn signal(self) -> nix::sys::signal::Signal {
+        use nix::sys::signal::Signal;
+        match self {
+            StopSignal::Term => Signal::SIGTERM,
+            StopSignal::Hup => Signal::SIGHUP,
+            StopSignal::Int => Signal::SIGINT,
+            StopSignal::Quit => Signal::SIGQUIT,
+            StopSignal::Kill => Signal::SIGKILL,
+            StopSignal::Usr1 => Signal::SIGUSR1,
+            StopSignal::Usr2 => Signal::SIGUSR2,
+        }
+    }
+}
+
+impl FromStr for StopSignal {
+    type Err = anyhow::Error;
+
+    // Accepts names with or without the 'SIG' prefix, like supervisord.
+    fn from_str(value: &str) -> Result<Self> {
+        let name = value.to_uppercase();
+        match name.strip_prefix("SIG").unwrap_or(&name) {
+            "TERM" => Ok(StopSignal::Term),
+            "HUP" => Ok(StopSignal::Hup),
+            "INT" => Ok(StopSignal::Int),
+            "QUIT" => Ok(StopSignal::Quit),
+            "KILL" => Ok(StopSignal::Kill),
+            "USR1" => Ok(StopSignal::Usr1),
+            "USR2" => Ok(StopSignal::Usr2),
+            _ => Err(format_err!("unsupported stop signal '{value}'")),
+        }
+    }
+}
+
+// Requests to the daemon, sent as text over its socket.
 #[derive(Debug)]
 pub enum Action {
-    Serve,
-    Start,
-    Stop,
-    Restart,
-    Reload,
-    Kill,
-    Status,
+    // Actions taking program names apply to every program if none is given.
+    Start(Vec<String>),
+    Stop(Vec<String>),
+    Restart(Vec<String>),
+    Reload(Vec<String>),
+    Kill(Vec<String>),
+    Status(StatusOptions),
     Exit,
+    // Like Exit, and waits for the daemon to go away.
+    ExitWait,
+    Reread,
+    Update,
+    // Re-executes the sup binary in place, keeping programs running.
+    Upgrade,
+    Logs(LogsOptions),
+    Attach(AttachOptions),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatusOptions {
+    pub programs: Vec<String>,
+    pub json: bool,
+}
+
+impl StatusOptions {
+    fn parse(args: &[&str]) -> Result<Self> {
+        let mut opts = StatusOptions {
+            programs: Vec::new(),
+            json: false,
+        };
+        for arg in args {
+            match *arg {
+                "--json" => opts.json = true,
+                _ if !arg.starts_with('-') => opts.programs.push(String::from(*arg)),
+                _ => return Err(format_err!("unknown flag '{arg}' for action status")),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+impl Display for StatusOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.json {
+            write!(f, " --json")?;
+        }
+        for program in &self.programs {
+            write!(f, " {program}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogsOptions {
+    pub lines: usize,
+    pub follow: bool,
+    pub stderr: bool,
+    // May be omitted if there is only one program.
+    pub program: Option<String>,
+}
+
+impl LogsOptions {
+    fn parse(args: &[&str]) -> Result<Self> {
+        let mut opts = LogsOptions {
+            lines: 10,
+            follow: false,
+            stderr: false,
+            program: None,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                "-n" => {
+                    let n = args
+                        .next()
+                        .context("expected number of lines after flag '-n'")?;
+                    opts.lines = n
+                        .parse()
+                        .context(format!("invalid number of lines '{n}'"))?;
+                }
+                "-f" => opts.follow = true,
+                "--stderr" => opts.stderr = true,
+                _ if !arg.starts_with('-') && opts.program.is_none() => {
+                    opts.program = Some(String::from(*arg))
+                }
+                _ => return Err(format_err!("unknown flag '{arg}' for action logs")),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+impl Display for LogsOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "-n {}", self.lines)?;
+        if self.follow {
+            write!(f, " -f")?;
+        }
+        if self.stderr {
+            write!(f, " --stderr")?;
+        }
+        if let Some(program) = &self.program {
+            write!(f, " {program}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttachOptions {
+    pub stdin: bool,
+    // Only used by the client, never sent to the server.
+    pub detach_keys: Vec<u8>,
+    // May be omitted if there is only one program.
+    pub program: Option<String>,
+}
+
+impl AttachOptions {
+    fn parse(args: &[&str]) -> Result<Self> {
+        let mut opts = AttachOptions {
+            stdin: false,
+            detach_keys: parse_keys("ctrl-p,ctrl-q")?,
+            program: None,
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match *arg {
+                "--stdin" => opts.stdin = true,
+                "--detach-keys" => {
+                    let keys = args
+                        .next()
+                        .context("expected key sequence after flag '--detach-keys'")?;
+                    opts.detach_keys = parse_keys(keys)?;
+                }
+                _ if !arg.starts_with('-') && opts.program.is_none() => {
+                    opts.program = Some(String::from(*arg))
+                }
+                _ => return Err(format_err!("unknown flag '{arg}' for action attach")),
+            }
+        }
+        Ok(opts)
+    }
+}
+
+impl Display for AttachOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.stdin {
+            write!(f, " --stdin")?;
+        }
+        if let Some(program) = &self.program {
+            write!(f, " {program}")?;
+        }
+        Ok(())
+    }
+}
+
+// Parses key sequence like 'ctrl-p,ctrl-q' or 'ctrl-a,d' into bytes.
+pub fn parse_keys(keys: &str) -> Result<Vec<u8>> {
+    keys.split(',')
+        .map(|key| match key.strip_prefix("ctrl-") {
+            Some(k) if k.len() == 1 && k.as_bytes()[0].is_ascii_lowercase() => {
+                Ok(k.as_bytes()[0] - b'a' + 1)
+            }
+            None if key.len() == 1 && key.is_ascii() => Ok(key.as_bytes()[0]),
+            _ => Err(format_err!("invalid key '{key}' in key sequence '{keys}'")),
+        })
+        .collect()
+}
+
+// Outcome of an action for one program. Actions on programs reply with one
+// for each program acted on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProgramResult {
+    pub program: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
 impl Action {
-    pub fn from_args() -> Result<Action> {
-        let args: Vec<String> = std::env::args().collect();
-        check_args(&args);
+    pub const NAMES: &'static [&'static str] = &[
+        "start",
+        "stop",
+        "restart",
+        "reload",
+        "kill",
+        "status",
+        "exit",
+        "exit-wait",
+        "reread",
+        "update",
+        "upgrade",
+        "logs",
+        "attach",
+    ];
 
-        let mut action = String::from("serve");
-        if args.len() == 4 {
-            action = args[3].clone();
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Start(_) => "start",
+            Action::Stop(_) => "stop",
+            Action::Restart(_) => "restart",
+            Action::Reload(_) => "reload",
+            Action::Kill(_) => "kill",
+            Action::Status(_) => "status",
+            Action::Exit => "exit",
+            Action::ExitWait => "exit-wait",
+            Action::Reread => "reread",
+            Action::Update => "update",
+            Action::Upgrade => "upgrade",
+            Action::Logs(_) => "logs",
+            Action::Attach(_) => "attach",
+        }
+    }
+
+    // Actions changing nothing, attaching only if not forwarding stdin.
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Action::Status(_) | Action::Reread | Action::Logs(_) => true,
+            Action::Attach(opts) => !opts.stdin,
+            _ => false,
         }
-        Ok(Action::from(action.as_str()))
     }
 }
 
 impl Display for Action {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Action::Serve => write!(f, "serve"),
-            Action::Start => write!(f, "start"),
-            Action::Stop => write!(f, "stop"),
-            Action::Restart => write!(f, "restart"),
-            Action::Reload => write!(f, "reload"),
-            Action::Kill => write!(f, "kill"),
-            Action::Status => write!(f, "status"),
+            Action::Start(names) => write_names(f, "start", names),
+            Action::Stop(names) => write_names(f, "stop", names),
+            Action::Restart(names) => write_names(f, "restart", names),
+            Action::Reload(names) => write_names(f, "reload", names),
+            Action::Kill(names) => write_names(f, "kill", names),
+            Action::Status(opts) => write!(f, "status{opts}"),
             Action::Exit => write!(f, "exit"),
+            Action::ExitWait => write!(f, "exit-wait"),
+            Action::Reread => write!(f, "reread"),
+            Action::Update => write!(f, "update"),
+            Action::Upgrade => write!(f, "upgrade"),
+            Action::Logs(opts) => write!(f, "logs {opts}"),
+            Action::Attach(opts) => write!(f, "attach{opts}"),
         }
     }
 }
 
-impl From<&str> for Action {
-    fn from(value: &str) -> Self {
-        match value {
-            x if x == "serve" => Action::Serve,
-            x if x == "start" => Action::Start,
-            x if x == "stop" => Action::Stop,
-            x if x == "restart" => Action::Restart,
-            x if x == "reload" => Action::Reload,
-            x if x == "kill" => Action::Kill,
-            x if x == "status" => Action::Status,
-            x if x == "exit" => Action::Exit,
-            _ => panic!("BUG: unknown action '{}'", value),
-        }
-    }
-}
-
-fn check_args(args: &Vec<String>) {
-    if args.len() < 2 || args.len() > 4 {
-        print_help();
-        std::process::exit(1);
-    }
-    if args[1] == "-h" || args[1] == "-v" {
-        print_help();
-        std::process::exit(0);
-    }
-    if args[1] != "-c" {
-        print_help();
-        std::process::exit(1);
-    }
-    if args.len() == 2 {
-        println!("expected config file path after flag '-c'");
-        std::process::exit(1);
-    }
-}
-
-fn print_help() {
-    println!("Usage:");
-    println!("    sup -h                          # show this message");
-    println!("    sup -v                          # show this message");
-    println!("    sup -c config.toml              # start sup daemon");
-    println!("    sup -c config.toml start        # start program asynchronously");
-    println!("    sup -c config.toml start-wait   # wait program to start");
-    println!("    sup -c config.toml stop         # stop program asynchronously");
-    println!("    sup -c config.toml stop-wait    # wait program to stop");
-    println!("    sup -c config.toml restart      # restart program asynchronously");
-    println!("    sup -c config.toml restart-wait # wait program to restart");
-    println!("    sup -c config.toml reload       # reload program");
-    println!("    sup -c config.toml kill         # kill program and all child processes");
-    println!("    sup -c config.toml status       # print status of program");
-    println!(
-        "    sup -c config.toml exit         # exit the sup daemon and the process asynchronously"
-    );
-    println!("    sup -c config.toml exit-wait    # wait the sup daemon and the process to exit");
-    println!("");
-    println!("Sup version: v{}", env!("CARGO_PKG_VERSION"));
-    println!("");
-    println!("Check more on: https://github.com/sequix/sup-rust");
+fn write_names(
+    f: &mut std::fmt::Formatter<'_>,
+    action: &str,
+    names: &[String],
+) -> std::fmt::Result {
+    write!(f, "{action}")?;
+    for name in names {
+        write!(f, " {name}")?;
+    }
+    Ok(())
+}
+
+impl FromStr for Action {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let mut words = value.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let opts: Vec<&str> = words.collect();
+        let names = || opts.iter().map(|n| String::from(*n)).collect();
+
+        let action = match name {
+            "start" => return Ok(Action::Start(names())),
+            "stop" => return Ok(Action::Stop(names())),
+            "restart" => return Ok(Action::Restart(names())),
+            "reload" => return Ok(Action::Reload(names())),
+            "kill" => return Ok(Action::Kill(names())),
+            "status" => return Ok(Action::Status(StatusOptions::parse(&opts)?)),
+            "exit" => Action::Exit,
+            "exit-wait" => Action::ExitWait,
+            "reread" => Action::Reread,
+            "update" => Action::Update,
+            "upgrade" => Action::Upgrade,
+            "logs" => return Ok(Action::Logs(LogsOptions::parse(&opts)?)),
+            "attach" => return Ok(Action::Attach(AttachOptions::parse(&opts)?)),
+            _ => return Err(format_err!("unknown action '{name}'")),
+        };
+        if !opts.is_empty() {
+            return Err(format_err!("action '{name}' takes no arguments"));
+        }
+        Ok(action)
+    }
 }
@@ -0,0 +1,202 @@
+"use strict";
+
+// Lines kept in the log view, older ones are dropped.
+const MAX_LOG_LINES = 1000;
+// How often the table is refreshed, besides on every event.
+const REFRESH_MS = 5000;
+
+let token = localStorage.getItem("sup-token") || "";
+let events = null;
+let logs = null;
+
+function api(path, options = {}) {
+  options.headers = { Authorization: "Bearer " + token };
+  return fetch(path, options).then(async (rsp) => {
+    if (!rsp.ok) {
+      const body = await rsp.json().catch(() => ({}));
+      throw new Error(body.error || rsp.statusText);
+    }
+    return rsp;
+  });
+}
+
+function showMessage(text) {
+  document.getElementById("message").textContent = text;
+}
+
+function formatUptime(seconds) {
+  if (seconds === null) {
+    return "";
+  }
+  const d = Math.floor(seconds / 86400);
+  const h = Math.floor((seconds % 86400) / 3600);
+  const m = Math.floor((seconds % 3600) / 60);
+  const s = seconds % 60;
+  if (d > 0) {
+    return `${d}d ${h}h`;
+  }
+  if (h > 0) {
+    return `${h}h ${m}m`;
+  }
+  return `${m}m ${s}s`;
+}
+
+function cell(row, text, className) {
+  const td = row.insertCell();
+  td.textContent = text;
+  if (className) {
+    td.className = className;
+  }
+  return td;
+}
+
+function button(parent, label, onClick) {
+  const b = document.createElement("button");
+  b.type = "button";
+  b.textContent = label;
+  b.addEventListener("click", onClick);
+  parent.appendChild(b);
+}
+
+function act(name, action) {
+  showMessage(`${action} ${name}...`);
+  api(`/programs/${name}/${action}`, { method: "POST" })
+    .then(() => showMessage(""))
+    .catch((e) => showMessage(`failed to ${action} ${name}: ${e.message}`))
+    .finally(refresh);
+}
+
+function render(programs) {
+  const body = document.querySelector("#programs tbody");
+  body.replaceChildren();
+  for (const p of programs) {
+    const row = body.insertRow();
+    cell(row, p.name);
+    cell(row, p.status, "state " + p.status);
+    cell(row, p.pid === null ? "" : p.pid);
+    cell(row, formatUptime(p.uptimeSeconds));
+    cell(row, p.restarts);
+    const actions = cell(row, "");
+    button(actions, "Start", () => act(p.name, "start"));
+    button(actions, "Stop", () => act(p.name, "stop"));
+    button(actions, "Restart", () => act(p.name, "restart"));
+    button(actions, "Logs", () => openLogs(p.name));
+  }
+}
+
+function refresh() {
+  if (!token) {
+    return;
+  }
+  api("/programs")
+    .then((rsp) => rsp.json())
+    .then(render)
+    .catch((e) => showMessage(`failed to list programs: ${e.message}`));
+}
+
+// Reads server-sent events from the response, calling back with the name and
+// data of each, since EventSource can not send the token.
+async function readEvents(rsp, onEvent) {
+  const reader = rsp.body.pipeThrough(new TextDecoderStream()).getReader();
+  let buffered = "";
+  for (;;) {
+    const { value, done } = await reader.read();
+    if (done) {
+      return;
+    }
+    buffered += value;
+    let end;
+    while ((end = buffered.indexOf("\n\n")) >= 0) {
+      const block = buffered.slice(0, end);
+      buffered = buffered.slice(end + 2);
+      let name = "message";
+      const data = [];
+      for (const line of block.split("\n")) {
+        if (line.startsWith("event: ")) {
+          name = line.slice(7);
+        } else if (line.startsWith("data: ")) {
+          data.push(line.slice(6));
+        }
+      }
+      if (data.length > 0) {
+        onEvent(name, data.join("\n"));
+      }
+    }
+  }
+}
+
+// Keeps the table up to date as programs start and exit, reconnecting if the
+// stream breaks.
+function watchEvents() {
+  if (events) {
+    events.abort();
+  }
+  if (!token) {
+    return;
+  }
+  events = new AbortController();
+  const signal = events.signal;
+  api("/events", { signal })
+    .then((rsp) => readEvents(rsp, refresh))
+    .catch(() => {})
+    .finally(() => {
+      if (!signal.aborted) {
+        setTimeout(watchEvents, REFRESH_MS);
+      }
+    });
+}
+
+function openLogs(name) {
+  if (logs) {
+    logs.abort();
+  }
+  logs = new AbortController();
+  const pre = document.getElementById("logs-lines");
+  pre.textContent = "";
+  document.getElementById("logs-program").textContent = name;
+  document.getElementById("logs").hidden = false;
+
+  // eslint-disable-next-line no-control-regex
+  const ansi = /\x1b\[[0-9;?]*[@-~]/g;
+  api(`/programs/${name}/logs?tail=100&follow`, { signal: logs.signal })
+    .then((rsp) =>
+      readEvents(rsp, (_, line) => {
+        const follow = pre.scrollTop + pre.clientHeight >= pre.scrollHeight - 4;
+        pre.appendChild(document.createTextNode(line.replace(ansi, "") + "\n"));
+        while (pre.childNodes.length > MAX_LOG_LINES) {
+          pre.removeChild(pre.firstChild);
+        }
+        if (follow) {
+          pre.scrollTop = pre.scrollHeight;
+        }
+      })
+    )
+    .catch((e) => {
+      if (e.name !== "AbortError") {
+        showMessage(`failed to follow logs of ${name}: ${e.message}`);
+      }
+    });
+}
+
+function closeLogs() {
+  if (logs) {
+    logs.abort();
+    logs = null;
+  }
+  document.getElementById("logs").hidden = true;
+}
+
+document.getElementById("login").addEventListener("submit", (e) => {
+  e.preventDefault();
+  token = document.getElementById("token").value;
+  localStorage.setItem("sup-token", token);
+  showMessage("");
+  refresh();
+  watchEvents();
+});
+document.getElementById("logs-close").addEventListener("click", closeLogs);
+document.getElementById("token").value = token;
+
+refresh();
+watchEvents();
+setInterval(refresh, REFRESH_MS);
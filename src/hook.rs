@@ -0,0 +1,268 @@
+use std::{
+    io::Write,
+    process::{self, Stdio},
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Condvar, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::config;
+use anyhow::{format_err, Context, Result};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use serde::Serialize;
+
+// How many events a slow subscriber may lag behind before losing events.
+const SUBSCRIBER_BUFFER: usize = 256;
+
+lazy_static! {
+    // Live subscribers to events of every program.
+    static ref SUBSCRIBERS: Mutex<Vec<SyncSender<Arc<Published>>>> = Mutex::new(Vec::new());
+}
+
+// An event of a program as passed to hooks on stdin.
+pub struct Published {
+    pub program: String,
+    pub name: &'static str,
+    // when the event happened, for onStart when the process counts as started
+    pub time: SystemTime,
+    pub json: String,
+}
+
+pub fn subscribe() -> Receiver<Arc<Published>> {
+    let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+    SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+fn publish(event: Published) {
+    let event = Arc::new(event);
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    subscribers.retain(|s| match s.try_send(Arc::clone(&event)) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            warn!("event subscriber is too slow, dropping an event");
+            true
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    });
+}
+
+// Lifecycle events of the supervised process, passed to the configured hooks
+// as SUP_* environment variables and as a JSON document on stdin.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    #[serde(rename = "onStart")]
+    Start { pid: u32 },
+    #[serde(rename = "onExit")]
+    Exit {
+        pid: u32,
+        code: Option<i32>,
+        signal: Option<i32>,
+    },
+    #[serde(rename = "onFatal")]
+    Fatal { reason: String },
+    #[serde(rename = "onRotate")]
+    Rotate { path: String, rotated_path: String },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Start { .. } => "onStart",
+            Event::Exit { .. } => "onExit",
+            Event::Fatal { .. } => "onFatal",
+            Event::Rotate { .. } => "onRotate",
+        }
+    }
+
+    fn envs(&self) -> Vec<(String, String)> {
+        let mut envs = vec![(String::from("SUP_EVENT"), String::from(self.name()))];
+        let mut push = |k: &str, v: String| envs.push((String::from(k), v));
+
+        match self {
+            Event::Start { pid } => push("SUP_PID", pid.to_string()),
+            Event::Exit { pid, code, signal } => {
+                push("SUP_PID", pid.to_string());
+                if let Some(code) = code {
+                    push("SUP_EXIT_CODE", code.to_string());
+                }
+                if let Some(signal) = signal {
+                    push("SUP_EXIT_SIGNAL", signal.to_string());
+                }
+            }
+            Event::Fatal { reason } => push("SUP_REASON", reason.clone()),
+            Event::Rotate { path, rotated_path } => {
+                push("SUP_LOG_PATH", path.clone());
+                push("SUP_ROTATED_PATH", rotated_path.clone());
+            }
+        }
+        envs
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    time: String,
+    program: &'a str,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+pub struct Dispatcher {
+    settings: RwLock<Arc<Settings>>,
+    running: Mutex<usize>,
+    slot_freed: Condvar,
+}
+
+struct Settings {
+    conf: config::Events,
+    program: String,
+    work_dir: String,
+}
+
+impl Settings {
+    fn new(name: &str, conf: &config::Program) -> Self {
+        Settings {
+            conf: conf.events.clone(),
+            program: String::from(name),
+            work_dir: conf.process.work_dir.clone(),
+        }
+    }
+}
+
+impl Dispatcher {
+    pub fn new(name: &str, conf: &config::Program) -> Self {
+        Dispatcher {
+            settings: RwLock::new(Arc::new(Settings::new(name, conf))),
+            running: Mutex::new(0),
+            slot_freed: Condvar::new(),
+        }
+    }
+
+    // Takes hooks from the new config, hooks already running are not affected.
+    pub fn reload(&self, conf: &config::Program) {
+        let mut settings = self.settings.write().unwrap();
+        *settings = Arc::new(Settings::new(&settings.program, conf));
+    }
+
+    // Publishes the event to subscribers, and runs every hook configured for
+    // it in background, at most 'maxConcurrency' of them at the same time, the
+    // rest wait for a slot.
+    pub fn emit(self: &Arc<Self>, event: Event) {
+        let settings = Arc::clone(&self.settings.read().unwrap());
+        let time = SystemTime::now();
+        let payload = Payload {
+            time: chrono::DateTime::<chrono::Utc>::from(time)
+                .to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            program: &settings.program,
+            event: &event,
+        };
+        let json = serde_json::to_string(&payload).expect("BUG: unserializable event");
+        publish(Published {
+            program: settings.program.clone(),
+            name: event.name(),
+            time,
+            json: json.clone(),
+        });
+
+        let commands = match event {
+            Event::Start { .. } => &settings.conf.on_start,
+            Event::Exit { .. } => &settings.conf.on_exit,
+            Event::Fatal { .. } => &settings.conf.on_fatal,
+            Event::Rotate { .. } => &settings.conf.on_rotate,
+        };
+        if commands.is_empty() {
+            return;
+        }
+
+        let input = Arc::new(json.into_bytes());
+        let mut envs = event.envs();
+        envs.push((String::from("SUP_PROGRAM"), settings.program.clone()));
+        let envs = Arc::new(envs);
+
+        for command in commands.clone() {
+            let dispatcher = Arc::clone(self);
+            let settings = Arc::clone(&settings);
+            let input = Arc::clone(&input);
+            let envs = Arc::clone(&envs);
+            let name = event.name();
+
+            thread::spawn(move || {
+                dispatcher.acquire(settings.conf.max_concurrency);
+                info!("running {name} hook '{command}'");
+                let timeout = Duration::from_secs(settings.conf.timeout_seconds);
+                if let Err(e) = run(&command, &settings.work_dir, &envs, &input, timeout) {
+                    error!("{name} hook '{command}' failed: {e}");
+                }
+                dispatcher.release();
+            });
+        }
+    }
+
+    fn acquire(&self, max_concurrency: usize) {
+        let mut running = self.running.lock().unwrap();
+        if max_concurrency > 0 && *running >= max_concurrency {
+            warn!("too many hooks running, waiting for a free slot");
+        }
+        while max_concurrency > 0 && *running >= max_concurrency {
+            running = self.slot_freed.wait(running).unwrap();
+        }
+        *running += 1;
+    }
+
+    fn release(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.slot_freed.notify_one();
+    }
+}
+
+// Runs the command with 'sh -c', feeding input to its stdin, and kills it if it
+// does not finish within timeout. Zero timeout means waiting forever.
+pub fn run(
+    command: &str,
+    work_dir: &str,
+    envs: &[(String, String)],
+    input: &[u8],
+    timeout: Duration,
+) -> Result<()> {
+    let mut cmd = process::Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .envs(envs.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::piped());
+    if !work_dir.is_empty() {
+        cmd.current_dir(work_dir);
+    }
+    let mut child = cmd.spawn().context("failed to spawn hook")?;
+
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    thread::spawn(move || {
+        // hooks are free to ignore their stdin
+        let _ = stdin.write_all(&input);
+    });
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(es) = child.try_wait().context("failed to wait hook")? {
+            if es.success() {
+                return Ok(());
+            }
+            return Err(format_err!("hook exited with {es}"));
+        }
+        if !timeout.is_zero() && Instant::now() >= deadline {
+            child.kill().context("failed to kill timed out hook")?;
+            child.wait().context("failed to wait killed hook")?;
+            return Err(format_err!(
+                "hook timed out after {} seconds",
+                timeout.as_secs()
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
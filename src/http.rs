@@ -0,0 +1,532 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::io::{AsRawFd, FromRawFd},
+    str::FromStr,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    config::{self, Config, StopSignal},
+    hook, output, process, programs, rotate, upgrade,
+};
+use anyhow::{format_err, Context, Result};
+use log::{error, info, warn};
+use serde_json::json;
+
+// Requests carry nothing but a request line and headers, bodies are skipped.
+const MAX_HEAD_SIZE: u64 = 16 * 1024;
+const MAX_BODY_SIZE: u64 = 64 * 1024;
+// How long a client may take to send its request or take the response, so
+// stalled clients do not hold a thread forever. Event streams have none once
+// started, as they stay idle by design.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+// How often an idle event stream gets a comment, to notice gone clients.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+// Files of the dashboard, compiled in so it works offline, served without
+// a token as they hold no data. The page asks for the token instead.
+const ASSETS: &[(&str, &str, &str)] = &[
+    (
+        "/",
+        "text/html; charset=utf-8",
+        include_str!("dashboard/index.html"),
+    ),
+    (
+        "/dashboard.js",
+        "text/javascript; charset=utf-8",
+        include_str!("dashboard/dashboard.js"),
+    ),
+    (
+        "/dashboard.css",
+        "text/css; charset=utf-8",
+        include_str!("dashboard/dashboard.css"),
+    ),
+];
+
+// Serves the HTTP API in background.
+pub fn run(conf: &config::Http, programs: Arc<Mutex<programs::Programs>>) -> Result<()> {
+    let listener = match upgrade::take_listener("http") {
+        Some(fd) => unsafe { TcpListener::from_raw_fd(fd) },
+        None => TcpListener::bind(&conf.listen)
+            .context(format!("failed to listen on {} for HTTP API", conf.listen))?,
+    };
+    upgrade::register_listener("http", listener.as_raw_fd());
+    info!("serving HTTP API on {}", conf.listen);
+
+    std::thread::spawn(move || {
+        for client in listener.incoming() {
+            match client {
+                Ok(c) => {
+                    let programs = Arc::clone(&programs);
+                    std::thread::spawn(|| {
+                        if let Err(e) = handle_client(c, programs) {
+                            error!("failed to handle HTTP client: {e:#}");
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("failed to accept HTTP client: {e}");
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    // keyed by lower-cased name
+    headers: HashMap<String, String>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(status: u16, body: serde_json::Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: (body.to_string() + "\n").into_bytes(),
+        }
+    }
+
+    fn error(status: u16, message: impl Display) -> Self {
+        Response::json(status, json!({ "error": format!("{message:#}") }))
+    }
+}
+
+fn handle_client(mut c: TcpStream, programs: Arc<Mutex<programs::Programs>>) -> Result<()> {
+    let peer = c
+        .peer_addr()
+        .context("failed to get address of HTTP client")?;
+    c.set_read_timeout(Some(IO_TIMEOUT))
+        .and_then(|_| c.set_write_timeout(Some(IO_TIMEOUT)))
+        .context("failed to set timeouts of HTTP client")?;
+    let req = match read_request(&c) {
+        Ok(req) => req,
+        Err(e) => return write_response(&mut c, Response::error(400, e)),
+    };
+
+    let conf = Config::get();
+    let dashboard = conf.sup.http.as_ref().is_some_and(|http| http.dashboard);
+    if let Some((_, content_type, body)) = ASSETS.iter().find(|(path, ..)| *path == req.path) {
+        if dashboard && req.method == "GET" {
+            let rsp = Response {
+                status: 200,
+                content_type,
+                body: body.as_bytes().to_vec(),
+            };
+            return write_response(&mut c, rsp);
+        }
+    }
+
+    let token = conf.sup.http.as_ref().map(|http| http.token.as_str());
+    if !is_authorized(&req, token.unwrap_or_default()) {
+        warn!(
+            "denied HTTP request {} {} from {peer}",
+            req.method, req.path
+        );
+        return write_response(&mut c, Response::error(401, "invalid or missing token"));
+    }
+    info!(
+        "received HTTP request {} {} from {peer}",
+        req.method, req.path
+    );
+
+    let segments: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["events"]) => return serve_events(c, req.query.get("program").cloned()),
+        ("GET", ["programs", name, "logs"]) if req.query.contains_key("follow") => {
+            return serve_log_stream(c, &req, name, &programs)
+        }
+        _ => {}
+    }
+    let rsp = route(&req, &programs);
+    write_response(&mut c, rsp)
+}
+
+fn route(req: &Request, programs: &Mutex<programs::Programs>) -> Response {
+    let segments: Vec<&str> = req.path.trim_matches('/').split('/').collect();
+    match (req.method.as_str(), segments.as_slice()) {
+        ("GET", ["programs"]) => list_programs(programs),
+        ("GET", ["programs", name]) => with_program(programs, name, |name, proc| {
+            describe(name, proc).map(|program| Response::json(200, program))
+        }),
+        ("GET", ["programs", name, "logs"]) => logs(req, name),
+        ("POST", ["programs", name, action @ ("start" | "stop")]) => {
+            in_order(programs, name, action)
+        }
+        ("POST", ["programs", name, action]) => with_program(programs, name, |name, proc| {
+            match *action {
+                "restart" => proc.restart()?,
+                "signal" => {
+                    let sig = match req.query.get("signal") {
+                        Some(sig) => sig,
+                        None => {
+                            return Ok(Response::error(400, "missing query parameter 'signal'"))
+                        }
+                    };
+                    match StopSignal::from_str(sig) {
+                        Ok(sig) => proc.signal(sig.signal())?,
+                        Err(e) => return Ok(Response::error(400, e)),
+                    }
+                }
+                _ => return Ok(Response::error(404, format!("unknown action '{action}'"))),
+            }
+            describe(name, proc).map(|program| Response::json(200, program))
+        }),
+        (_, ["programs"] | ["programs", _] | ["programs", _, _] | ["events"]) => {
+            Response::error(405, format!("method {} not allowed", req.method))
+        }
+        _ => Response::error(404, format!("no such path {}", req.path)),
+    }
+}
+
+fn list_programs(programs: &Mutex<programs::Programs>) -> Response {
+    let selected = match programs.lock().unwrap().select(&[]) {
+        Ok(selected) => selected,
+        Err(e) => return Response::error(500, e),
+    };
+    let mut list = Vec::new();
+    for (name, proc) in selected {
+        match describe(&name, &proc.lock().unwrap()) {
+            Ok(program) => list.push(program),
+            Err(e) => return Response::error(500, e),
+        }
+    }
+    Response::json(200, json!(list))
+}
+
+// Runs the function on the program, responding 404 if there is no such
+// program and 500 if the function fails.
+fn with_program(
+    programs: &Mutex<programs::Programs>,
+    name: &str,
+    f: impl FnOnce(&str, &mut process::Process) -> Result<Response>,
+) -> Response {
+    let selected = programs.lock().unwrap().select_one(Some(name));
+    let (name, proc) = match selected {
+        Ok(selected) => selected,
+        Err(e) => return Response::error(404, e),
+    };
+    let rsp = f(&name, &mut proc.lock().unwrap());
+    rsp.unwrap_or_else(|e| Response::error(500, e))
+}
+
+// Starts the program after the programs it depends on, or stops it after the
+// programs depending on it.
+fn in_order(programs: &Mutex<programs::Programs>, name: &str, action: &str) -> Response {
+    let selected = programs.lock().unwrap().select_one(Some(name));
+    if let Err(e) = selected {
+        return Response::error(404, e);
+    }
+    let names = [String::from(name)];
+    let done = match action {
+        "start" => programs::for_each(programs, |p| p.start_order(&names), |proc| proc.start()),
+        _ => programs::for_each(programs, |p| p.stop_order(&names), |proc| proc.stop()),
+    };
+    match done.and_then(programs::joined) {
+        Ok(()) => with_program(programs, name, |name, proc| {
+            describe(name, proc).map(|program| Response::json(200, program))
+        }),
+        Err(e) => Response::error(500, e),
+    }
+}
+
+fn describe(name: &str, proc: &process::Process) -> Result<serde_json::Value> {
+    Ok(json!({
+        "name": name,
+        "status": proc.status()?,
+        "pid": proc.pid(),
+        "uptimeSeconds": proc.uptime().map(|t| t.as_secs()),
+        "restarts": proc.restarts(),
+        "nextRun": proc.next_run().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()),
+        "runs": proc.runs(),
+    }))
+}
+
+fn logs(req: &Request, name: &str) -> Response {
+    match tail_logs(req, name) {
+        Ok(lines) => Response {
+            status: 200,
+            content_type: "text/plain; charset=utf-8",
+            body: lines.concat(),
+        },
+        Err(rsp) => rsp,
+    }
+}
+
+// Reads the last lines of the log file of the program, as many as asked by
+// query parameter 'tail'.
+fn tail_logs(req: &Request, name: &str) -> Result<Vec<Vec<u8>>, Response> {
+    let tail = tail_lines(req)?;
+    let program = match Config::get().instance(name) {
+        Some(program) => program,
+        None => return Err(Response::error(404, format!("unknown program '{name}'"))),
+    };
+    rotate::Rotater::tail(&program.log, tail).map_err(|e| Response::error(500, e))
+}
+
+// How many lines of the log to return, by query parameter 'tail'.
+fn tail_lines(req: &Request) -> Result<usize, Response> {
+    match req.query.get("tail").map(|n| n.parse()) {
+        None => Ok(10),
+        Some(Ok(n)) => Ok(n),
+        Some(Err(_)) => Err(Response::error(400, "invalid query parameter 'tail'")),
+    }
+}
+
+// Streams the last lines of the log file and then live output of the program,
+// each line as a server-sent event, until the client goes away.
+fn serve_log_stream(
+    mut c: TcpStream,
+    req: &Request,
+    name: &str,
+    programs: &Mutex<programs::Programs>,
+) -> Result<()> {
+    let tail = match tail_lines(req) {
+        Ok(tail) => tail,
+        Err(rsp) => return write_response(&mut c, rsp),
+    };
+    let selected = programs.lock().unwrap().select_one(Some(name));
+    let logs = match selected {
+        Ok((_, proc)) => proc.lock().unwrap().follow_logs(tail, false),
+        Err(e) => return write_response(&mut c, Response::error(404, e)),
+    };
+    let (lines, live) = match logs {
+        Ok(logs) => logs,
+        Err(e) => return write_response(&mut c, Response::error(500, e)),
+    };
+
+    write_event_stream_head(&mut c)?;
+    for line in lines {
+        if c.write_all(line_event(&line).as_bytes()).is_err() {
+            return Ok(());
+        }
+    }
+    // output arrives as read, so gather it into lines per stream
+    let mut pending = [Vec::new(), Vec::new()];
+    stream_events(c, live, |chunk| {
+        let pending = match chunk.stream {
+            output::Stream::Stdout => &mut pending[0],
+            output::Stream::Stderr => &mut pending[1],
+        };
+        pending.extend_from_slice(&chunk.data);
+        let end = match pending.iter().rposition(|&b| b == b'\n') {
+            Some(end) => end,
+            None if pending.len() > output::MAX_LINE => pending.len() - 1,
+            None => return None,
+        };
+        let lines: Vec<u8> = pending.drain(..=end).collect();
+        Some(
+            lines
+                .split_inclusive(|&b| b == b'\n')
+                .map(line_event)
+                .collect(),
+        )
+    });
+    Ok(())
+}
+
+// Server-sent event data may not span lines, and output lines end with one.
+fn line_event(line: &[u8]) -> String {
+    let line = String::from_utf8_lossy(line);
+    format!(
+        "data: {}\n\n",
+        line.trim_end_matches(['\n', '\r']).replace('\r', "")
+    )
+}
+
+// Streams events of every program, or only of the given one, as server-sent
+// events until the client goes away.
+fn serve_events(mut c: TcpStream, program: Option<String>) -> Result<()> {
+    let events = hook::subscribe();
+    write_event_stream_head(&mut c)?;
+    stream_events(c, events, |event| {
+        if program.as_ref().is_some_and(|p| *p != event.program) {
+            return None;
+        }
+        Some(format!("event: {}\ndata: {}\n\n", event.name, event.json))
+    });
+    Ok(())
+}
+
+fn write_event_stream_head(c: &mut TcpStream) -> Result<()> {
+    c.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: text/event-stream\r\n\
+          Cache-Control: no-cache\r\n\
+          Connection: close\r\n\r\n",
+    )
+    .context("failed to write HTTP response")?;
+    c.set_read_timeout(None)
+        .and_then(|_| c.set_write_timeout(None))
+        .context("failed to clear timeouts of HTTP client")
+}
+
+// Writes the received items formatted as events until the client goes away,
+// skipping items formatted as None.
+fn stream_events<T>(
+    mut c: TcpStream,
+    items: Receiver<Arc<T>>,
+    mut format: impl FnMut(Arc<T>) -> Option<String>,
+) {
+    loop {
+        let written = match items.recv_timeout(KEEP_ALIVE) {
+            Ok(item) => match format(item) {
+                Some(event) => c.write_all(event.as_bytes()),
+                None => continue,
+            },
+            Err(RecvTimeoutError::Timeout) => c.write_all(b": keep-alive\n\n"),
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if written.is_err() {
+            // client went away
+            break;
+        }
+    }
+}
+
+fn read_request(c: &TcpStream) -> Result<Request> {
+    let mut reader = BufReader::new(c.take(MAX_HEAD_SIZE));
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .context("failed to read request")?;
+
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+            (method, target)
+        }
+        _ => return Err(format_err!("malformed request line")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = percent_decode(path)?;
+    let query = query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            // '+' stands for a space in query strings, as in forms
+            let decode = |s: &str| percent_decode(&s.replace('+', " "));
+            Ok((decode(k)?, decode(v)?))
+        })
+        .collect::<Result<_>>()?;
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut header = String::new();
+        if reader
+            .read_line(&mut header)
+            .context("failed to read request")?
+            == 0
+        {
+            return Err(format_err!("incomplete request headers"));
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| format_err!("malformed header '{header}'"))?;
+        headers.insert(name.trim().to_ascii_lowercase(), String::from(value.trim()));
+    }
+
+    // drain the body, closing with unread data would reset the connection
+    let length: u64 = match headers.get("content-length") {
+        Some(length) => length
+            .parse()
+            .map_err(|_| format_err!("invalid Content-Length '{length}'"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(format_err!("request body too large"));
+    }
+    // part of it may be buffered already
+    let buffered = reader.buffer().len() as u64;
+    reader.consume(buffered.min(length) as usize);
+    reader.get_mut().set_limit(length.saturating_sub(buffered));
+    std::io::copy(reader.get_mut(), &mut std::io::sink()).context("failed to read request body")?;
+
+    Ok(Request {
+        method: String::from(method),
+        path,
+        query,
+        headers,
+    })
+}
+
+// Decodes '%XX' escapes, like '%3A' for ':'.
+fn percent_decode(s: &str) -> Result<String> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'%' {
+            decoded.push(b);
+            continue;
+        }
+        let mut digit = || bytes.next().and_then(|b| char::from(b).to_digit(16));
+        match (digit(), digit()) {
+            (Some(high), Some(low)) => decoded.push((high * 16 + low) as u8),
+            _ => return Err(format_err!("invalid percent-encoding in '{s}'")),
+        }
+    }
+    String::from_utf8(decoded).map_err(|_| format_err!("invalid UTF-8 in '{s}'"))
+}
+
+// Compares the bearer token in constant time, so its bytes can not be guessed
+// one by one. An empty token never matches.
+fn is_authorized(req: &Request, token: &str) -> bool {
+    let given = req
+        .headers
+        .get("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    !token.is_empty()
+        && given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn write_response(c: &mut TcpStream, rsp: Response) -> Result<()> {
+    let reason = match rsp.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        rsp.status,
+        rsp.content_type,
+        rsp.body.len()
+    );
+    if rsp.status == 401 {
+        head.push_str("WWW-Authenticate: Bearer\r\n");
+    }
+    head.push_str("\r\n");
+
+    c.write_all(head.as_bytes())
+        .and_then(|_| c.write_all(&rsp.body))
+        .context("failed to write HTTP response")
+}
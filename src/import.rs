@@ -0,0 +1,712 @@
+use std::{fmt::Display, path::Path};
+
+use crate::config::{self, StopSignal};
+use anyhow::{format_err, Context, Result};
+use toml::value::{Table, Value};
+
+// Defaults of supervisord differing from those of sup, emitted when the
+// setting is not given, so the imported programs behave the same.
+const SUPERVISORD_MAX_SIZE: &str = "50MB";
+const SUPERVISORD_MAX_BACKUPS: &str = "10";
+// Foreman gives each process type a port, 100 apart starting from 5000.
+const FOREMAN_BASE_PORT: usize = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Source {
+    Supervisord,
+    Procfile,
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Supervisord => write!(f, "supervisord"),
+            Source::Procfile => write!(f, "procfile"),
+        }
+    }
+}
+
+// Converts the file into a sup config in TOML, returning it along with
+// warnings about settings which could not be mapped.
+pub fn import(from: Source, path: &str) -> Result<(String, Vec<String>)> {
+    let src = std::fs::read_to_string(path).context(format!("failed to read file {path}"))?;
+
+    let mut warnings = Vec::new();
+    let programs = match from {
+        Source::Supervisord => {
+            let here = Path::new(path)
+                .parent()
+                .filter(|d| !d.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            from_supervisord(&src, &here.to_string_lossy(), &mut warnings)?
+        }
+        Source::Procfile => from_procfile(&src, &mut warnings)?,
+    };
+
+    let mut conf = Table::new();
+    conf.insert(String::from("programs"), Value::Table(programs));
+    let toml = toml::to_string(&Value::Table(conf)).context("failed to serialize config")?;
+    Ok((
+        format!("# Imported from {from} {path} by 'sup import'.\n{toml}"),
+        warnings,
+    ))
+}
+
+fn from_supervisord(src: &str, here: &str, warnings: &mut Vec<String>) -> Result<Table> {
+    let mut programs = Table::new();
+
+    for section in parse_ini(src)? {
+        let name = match section.name.strip_prefix("program:") {
+            Some(name) => name.trim(),
+            None if section.name == "include" => {
+                warnings.push(String::from(
+                    "section [include] is ignored, import the included files separately and list them in 'sup.include'",
+                ));
+                continue;
+            }
+            None => {
+                warnings.push(format!("section [{}] is ignored", section.name));
+                continue;
+            }
+        };
+        let program = program_name(name, warnings);
+        if programs.contains_key(&program) {
+            return Err(format_err!("program '{program}' is defined twice"));
+        }
+
+        let mut warn = |message: String| warnings.push(format!("[{}] {message}", section.name));
+        let mut process = Table::new();
+        let mut log = Table::new();
+        let mut auto_start = String::from("true");
+        let mut max_size = String::from(SUPERVISORD_MAX_SIZE);
+        let mut max_backups = String::from(SUPERVISORD_MAX_BACKUPS);
+        let mut command = None;
+
+        for (key, value) in &section.entries {
+            let value = interpolate(value, name, here, &mut warn);
+            match key.as_str() {
+                "command" => command = Some(value),
+                "directory" => {
+                    process.insert(String::from("workDir"), Value::String(value));
+                }
+                "environment" => match parse_environment(&value) {
+                    Ok(envs) => {
+                        process.insert(String::from("envs"), Value::Table(envs));
+                    }
+                    Err(e) => warn(format!("environment is ignored: {e:#}")),
+                },
+                "autostart" => auto_start = value,
+                "autorestart" => {
+                    let strategy = match value.to_lowercase().as_str() {
+                        "true" => config::RestartStrategy::Always,
+                        "false" => config::RestartStrategy::None,
+                        "unexpected" => config::RestartStrategy::OnFailure,
+                        _ => {
+                            warn(format!("autorestart '{value}' is ignored"));
+                            continue;
+                        }
+                    };
+                    process.insert(
+                        String::from("restartStrategy"),
+                        Value::String(strategy.to_string()),
+                    );
+                }
+                "startsecs" => match value.parse::<i64>() {
+                    Ok(secs) if secs >= 0 => {
+                        process.insert(String::from("startSeconds"), Value::Integer(secs));
+                    }
+                    _ => warn(format!("startsecs '{value}' is ignored")),
+                },
+                "stopsignal" => match value.parse::<StopSignal>() {
+                    Ok(signal) => {
+                        let signal = Value::try_from(signal)?;
+                        process.insert(String::from("stopSignal"), signal);
+                    }
+                    Err(e) => warn(format!("{e}, stopping with TERM")),
+                },
+                "stdout_logfile" => match value.as_str() {
+                    "AUTO" => {}
+                    "NONE" => warn(String::from(
+                        "stdout_logfile NONE is ignored, sup always keeps a log",
+                    )),
+                    _ => {
+                        log.insert(String::from("path"), Value::String(value));
+                    }
+                },
+                "stdout_logfile_maxbytes" => max_size = value,
+                "stdout_logfile_backups" => max_backups = value,
+                "redirect_stderr" => {}
+                "stderr_logfile" | "stderr_logfile_maxbytes" | "stderr_logfile_backups" => warn(
+                    format!("{key} is ignored, sup writes stderr to the same log as stdout"),
+                ),
+                "exitcodes" if value == "0" => {}
+                "exitcodes" => warn(format!(
+                    "exitcodes '{value}' is ignored, sup only expects exit code 0"
+                )),
+                "numprocs" => match value.parse::<i64>() {
+                    Ok(1) => {}
+                    Ok(n) if n > 1 => {
+                        process.insert(String::from("instances"), Value::Integer(n));
+                    }
+                    _ => warn(format!("numprocs '{value}' is ignored")),
+                },
+                "process_name" if value == name => {}
+                "process_name" => warn(format!(
+                    "process_name '{value}' is ignored, instances are named '<program>:<instance>'"
+                )),
+                _ => warn(format!("{key} cannot be mapped, ignored")),
+            }
+        }
+
+        let command = match command {
+            Some(command) => command,
+            None => {
+                warn(String::from("program has no command, skipped"));
+                continue;
+            }
+        };
+        let mut words = split_words(&command)
+            .with_context(|| format!("invalid command of [{}]", section.name))?
+            .into_iter();
+        let path = words
+            .next()
+            .with_context(|| format!("empty command of [{}]", section.name))?;
+        let mut command = Table::new();
+        command.insert(String::from("path"), Value::String(path));
+        command.insert(
+            String::from("args"),
+            Value::Array(words.map(Value::String).collect()),
+        );
+        command.extend(process);
+        let mut process = command;
+        match parse_bool(&auto_start) {
+            Some(auto_start) => {
+                process.insert(String::from("autoStart"), Value::Boolean(auto_start));
+            }
+            None => warn(format!("autostart '{auto_start}' is ignored")),
+        }
+
+        match parse_bytes(&max_size) {
+            Some(0) => warn(String::from(
+                "unlimited stdout_logfile_maxbytes is not supported, rotating at 128 MiB",
+            )),
+            // sup rotates at whole MiB
+            Some(bytes) => {
+                let mib = bytes.div_ceil(1024 * 1024) as i64;
+                log.insert(String::from("maxSize"), Value::Integer(mib));
+            }
+            None => warn(format!("stdout_logfile_maxbytes '{max_size}' is ignored")),
+        }
+        match max_backups.parse::<i64>() {
+            Ok(0) => warn(String::from(
+                "stdout_logfile_backups 0 is not supported, sup keeps every backup",
+            )),
+            Ok(backups) if backups > 0 => {
+                log.insert(String::from("maxBackups"), Value::Integer(backups));
+            }
+            _ => warn(format!("stdout_logfile_backups '{max_backups}' is ignored")),
+        }
+
+        programs.insert(program, Value::Table(program_table(process, log)));
+    }
+    Ok(programs)
+}
+
+fn from_procfile(src: &str, warnings: &mut Vec<String>) -> Result<Table> {
+    let mut programs = Table::new();
+
+    for (i, line) in src.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, command) = line
+            .split_once(':')
+            .with_context(|| format!("line {}: expected 'name: command'", i + 1))?;
+        let program = program_name(name.trim(), warnings);
+        if programs.contains_key(&program) {
+            return Err(format_err!("program '{program}' is defined twice"));
+        }
+
+        // like foreman, run the command through the shell
+        let mut process = Table::new();
+        process.insert(String::from("path"), Value::String(String::from("/bin/sh")));
+        process.insert(
+            String::from("args"),
+            Value::Array(vec![
+                Value::String(String::from("-c")),
+                Value::String(String::from(command.trim())),
+            ]),
+        );
+        process.insert(String::from("autoStart"), Value::Boolean(true));
+        let port = FOREMAN_BASE_PORT + 100 * programs.len();
+        let mut envs = Table::new();
+        envs.insert(String::from("PORT"), Value::String(port.to_string()));
+        process.insert(String::from("envs"), Value::Table(envs));
+
+        programs.insert(program, Value::Table(program_table(process, Table::new())));
+    }
+    Ok(programs)
+}
+
+fn program_table(process: Table, log: Table) -> Table {
+    let mut program = Table::new();
+    program.insert(String::from("process"), Value::Table(process));
+    if !log.is_empty() {
+        program.insert(String::from("log"), Value::Table(log));
+    }
+    program
+}
+
+// Replaces characters sup does not allow in program names.
+fn program_name(name: &str, warnings: &mut Vec<String>) -> String {
+    if config::is_valid_name(name) {
+        return String::from(name);
+    }
+    let renamed: String = name
+        .chars()
+        .map(|c| {
+            if config::is_valid_name(&c.to_string()) {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    warnings.push(format!("program '{name}' is renamed to '{renamed}'"));
+    renamed
+}
+
+struct Section {
+    name: String,
+    entries: Vec<(String, String)>,
+}
+
+// Parses the INI dialect of supervisord: 'key = value' or 'key: value',
+// comments starting with ';' or '#', and indented continuation lines.
+fn parse_ini(src: &str) -> Result<Vec<Section>> {
+    let mut sections: Vec<Section> = Vec::new();
+
+    for (i, raw) in src.lines().enumerate() {
+        let line = raw.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+
+        if raw.starts_with(char::is_whitespace) {
+            if let Some((_, value)) = sections.last_mut().and_then(|s| s.entries.last_mut()) {
+                value.push(' ');
+                value.push_str(strip_comment(line));
+                continue;
+            }
+        }
+
+        if let Some(header) = line.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .with_context(|| format!("line {}: invalid section header", i + 1))?;
+            sections.push(Section {
+                name: String::from(name.trim()),
+                entries: Vec::new(),
+            });
+            continue;
+        }
+
+        let section = sections
+            .last_mut()
+            .with_context(|| format!("line {}: key outside of any section", i + 1))?;
+        let (key, value) = line
+            .split_once(['=', ':'])
+            .with_context(|| format!("line {}: expected 'key = value'", i + 1))?;
+        section.entries.push((
+            key.trim().to_lowercase(),
+            String::from(strip_comment(value.trim())),
+        ));
+    }
+    Ok(sections)
+}
+
+// Inline comments start with ' ;'.
+fn strip_comment(value: &str) -> &str {
+    match value.find(" ;") {
+        Some(i) => value[..i].trim_end(),
+        None => value,
+    }
+}
+
+// Expands the '%(name)s' expressions supervisord supports in values, those
+// of environment variables with their current values.
+fn interpolate(value: &str, program: &str, here: &str, warn: &mut impl FnMut(String)) -> String {
+    let mut expanded = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find('%') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(r) = rest.strip_prefix("%%") {
+            expanded.push('%');
+            rest = r;
+            continue;
+        }
+        let expr = rest
+            .strip_prefix("%(")
+            .and_then(|r| r.split_once(')'))
+            .and_then(|(name, r)| {
+                let spec = r.find(|c: char| c.is_ascii_alphabetic())?;
+                Some((name, r[..=spec].len() + name.len() + 3))
+            });
+        let (name, len) = match expr {
+            Some(expr) => expr,
+            None => {
+                expanded.push('%');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let replacement = match name {
+            "program_name" | "group_name" => Some(String::from(program)),
+            "here" => Some(String::from(here)),
+            // filled in for each instance by sup
+            "process_num" => Some(String::from("{instance}")),
+            _ => name
+                .strip_prefix("ENV_")
+                .and_then(|env| std::env::var(env).ok()),
+        };
+        match replacement {
+            Some(replacement) => {
+                if name.starts_with("ENV_") {
+                    warn(format!("%({name})s is expanded to its current value"));
+                }
+                expanded.push_str(&replacement);
+            }
+            None => {
+                warn(format!("cannot expand {}", &rest[..len]));
+                expanded.push_str(&rest[..len]);
+            }
+        }
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+// Splits the command into words like a shell, honoring quotes and
+// backslashes, without expanding anything.
+fn split_words(command: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = command.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(format_err!("unterminated single quote")),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(format_err!("unterminated double quote")),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(format_err!("unterminated double quote")),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                if let Some(c) = chars.next() {
+                    word.push(c);
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+// Parses 'KEY="value",KEY2=value2' into a table.
+fn parse_environment(value: &str) -> Result<Table> {
+    let mut envs = Table::new();
+    let mut entry = String::new();
+    let mut quote = None;
+
+    let mut entries = Vec::new();
+    for c in value.chars() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (',', None) => entries.push(std::mem::take(&mut entry)),
+            (c, _) => entry.push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(format_err!("unterminated quote"));
+    }
+    entries.push(entry);
+
+    for entry in entries {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            continue;
+        }
+        let (key, value) = entry
+            .split_once('=')
+            .with_context(|| format!("expected 'KEY=value', found '{entry}'"))?;
+        envs.insert(String::from(key.trim()), Value::String(String::from(value)));
+    }
+    Ok(envs)
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+// Parses sizes like '50MB', '1GB' or '1024'.
+fn parse_bytes(value: &str) -> Option<u64> {
+    let value = value.trim().to_uppercase();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value.as_str(), ""),
+    };
+    let unit = match unit {
+        "" | "B" => 1,
+        "KB" => 1024,
+        "MB" => 1024 * 1024,
+        "GB" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    // an overflow is reported like any other unparsable size
+    number.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Value {
+        Value::String(String::from(value))
+    }
+
+    fn program<'a>(programs: &'a Table, name: &str) -> &'a Table {
+        programs[name].as_table().unwrap()
+    }
+
+    fn process<'a>(programs: &'a Table, name: &str) -> &'a Table {
+        program(programs, name)["process"].as_table().unwrap()
+    }
+
+    #[test]
+    fn ini_comments_and_continuations() {
+        let src = "\
+; a comment
+# another one
+[program:web]
+command = /bin/web ; inline comment
+environment = A=1,
+  B=2 ; continued
+directory: /srv
+";
+        let sections = parse_ini(src).unwrap();
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].name, "program:web");
+        assert_eq!(
+            sections[0].entries,
+            [
+                (String::from("command"), String::from("/bin/web")),
+                (String::from("environment"), String::from("A=1, B=2")),
+                (String::from("directory"), String::from("/srv")),
+            ]
+        );
+
+        assert!(parse_ini("key = value").is_err());
+        assert!(parse_ini("[program:web").is_err());
+        assert!(parse_ini("[program:web]\nno value").is_err());
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut warnings = Vec::new();
+        let mut warn = |message: String| warnings.push(message);
+        assert_eq!(
+            interpolate(
+                "%(here)s/%(program_name)s-%(process_num)02d.log",
+                "web",
+                "/etc",
+                &mut warn
+            ),
+            "/etc/web-{instance}.log"
+        );
+        assert_eq!(
+            interpolate("100%% and 5%", "web", "/etc", &mut warn),
+            "100% and 5%"
+        );
+        assert_eq!(
+            interpolate("%(unknown)s", "web", "/etc", &mut warn),
+            "%(unknown)s"
+        );
+        assert_eq!(warnings, ["cannot expand %(unknown)s"]);
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(
+            split_words(r#"/bin/sh -c 'echo "a  b"' "x \"y\" \n" a\ b"#).unwrap(),
+            ["/bin/sh", "-c", r#"echo "a  b""#, r#"x "y" \n"#, "a b"]
+        );
+        assert_eq!(split_words("  a   ''  ").unwrap(), ["a", ""]);
+        assert!(split_words("'open").is_err());
+        assert!(split_words("\"open").is_err());
+    }
+
+    #[test]
+    fn environment() {
+        let envs = parse_environment(r#"A="1,2",B='x=y', C=3,"#).unwrap();
+        assert_eq!(envs["A"], string("1,2"));
+        assert_eq!(envs["B"], string("x=y"));
+        assert_eq!(envs["C"], string("3"));
+        assert_eq!(envs.len(), 3);
+
+        assert!(parse_environment("A=\"1").is_err());
+        assert!(parse_environment("A").is_err());
+    }
+
+    #[test]
+    fn bytes() {
+        assert_eq!(parse_bytes("1024"), Some(1024));
+        assert_eq!(parse_bytes("10B"), Some(10));
+        assert_eq!(parse_bytes("2kb"), Some(2048));
+        assert_eq!(parse_bytes("50MB"), Some(50 * 1024 * 1024));
+        assert_eq!(parse_bytes("1GB"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_bytes("1TB"), None);
+        assert_eq!(parse_bytes("MB"), None);
+        assert_eq!(parse_bytes("99999999999999GB"), None);
+        assert_eq!(parse_bytes("99999999999999999999"), None);
+    }
+
+    #[test]
+    fn supervisord_keys() {
+        let src = "\
+[supervisord]
+logfile = /var/log/supervisord.log
+
+[program:web]
+command = \"/usr/bin/web server\" --port 80
+autorestart = unexpected
+startsecs = 5
+stopsignal = INT
+stdout_logfile_maxbytes = 1MB
+stdout_logfile_backups = 3
+stderr_logfile = /var/log/web.err
+priority = 10
+
+[program:worker]
+command = /bin/worker
+autostart = false
+autorestart = sometimes
+startsecs = -1
+stopsignal = NOPE
+stdout_logfile_maxbytes = 99999999999999GB
+";
+        let mut warnings = Vec::new();
+        let programs = from_supervisord(src, "/etc", &mut warnings).unwrap();
+
+        let web = process(&programs, "web");
+        assert_eq!(web["path"], string("/usr/bin/web server"));
+        assert_eq!(
+            web["args"],
+            Value::Array(vec![string("--port"), string("80")])
+        );
+        assert_eq!(web["restartStrategy"], string("on-failure"));
+        assert_eq!(web["startSeconds"], Value::Integer(5));
+        assert_eq!(web["stopSignal"], Value::try_from(StopSignal::Int).unwrap());
+        assert_eq!(web["autoStart"], Value::Boolean(true));
+        let log = program(&programs, "web")["log"].as_table().unwrap();
+        assert_eq!(log["maxSize"], Value::Integer(1));
+        assert_eq!(log["maxBackups"], Value::Integer(3));
+
+        let worker = process(&programs, "worker");
+        assert_eq!(worker["autoStart"], Value::Boolean(false));
+        assert!(!worker.contains_key("restartStrategy"));
+        assert!(!worker.contains_key("startSeconds"));
+        assert!(!worker.contains_key("stopSignal"));
+
+        let expected = [
+            "section [supervisord] is ignored",
+            "[program:web] stderr_logfile is ignored, sup writes stderr to the same log as stdout",
+            "[program:web] priority cannot be mapped, ignored",
+            "[program:worker] autorestart 'sometimes' is ignored",
+            "[program:worker] startsecs '-1' is ignored",
+            "[program:worker] stdout_logfile_maxbytes '99999999999999GB' is ignored",
+        ];
+        for message in expected {
+            assert!(
+                warnings.iter().any(|w| w == message),
+                "missing warning '{message}' in {warnings:?}"
+            );
+        }
+        assert!(warnings
+            .iter()
+            .any(|w| w.starts_with("[program:worker] ") && w.ends_with(", stopping with TERM")));
+        assert_eq!(warnings.len(), expected.len() + 1);
+    }
+
+    #[test]
+    fn procfile_ports() {
+        let src = "\
+# comment
+web: bundle exec rails server -p $PORT
+worker:   bundle exec sidekiq
+";
+        let mut warnings = Vec::new();
+        let programs = from_procfile(src, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+
+        let web = process(&programs, "web");
+        assert_eq!(web["path"], string("/bin/sh"));
+        assert_eq!(
+            web["args"],
+            Value::Array(vec![
+                string("-c"),
+                string("bundle exec rails server -p $PORT")
+            ])
+        );
+        assert_eq!(web["envs"]["PORT"], string("5000"));
+        assert_eq!(process(&programs, "worker")["envs"]["PORT"], string("5100"));
+
+        assert!(from_procfile("web", &mut warnings).is_err());
+        assert!(from_procfile("web: a\nweb: b", &mut warnings).is_err());
+    }
+}
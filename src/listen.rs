@@ -0,0 +1,261 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{CString, OsStr},
+    net::TcpListener,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, OwnedFd, RawFd},
+        net::{UnixListener, UnixStream},
+        process::CommandExt,
+    },
+    path::PathBuf,
+    process::Command,
+    str::FromStr,
+};
+
+use anyhow::{format_err, Context, Result};
+use nix::{errno::Errno, libc, poll};
+
+// Sockets passed to a child start at this descriptor, as in systemd's
+// socket activation protocol.
+const FIRST_FD: RawFd = 3;
+pub const MAX_SOCKETS: usize = 64;
+
+// Address of a socket sup listens on for a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    // host and port
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            match addr.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(Address::Tcp(String::from(addr)))
+                }
+                _ => Err(format_err!(
+                    "invalid address '{s}', expected 'tcp://<host>:<port>'"
+                )),
+            }
+        } else if let Some(path) = s.strip_prefix("unix://") {
+            match path.is_empty() {
+                true => Err(format_err!(
+                    "invalid address '{s}', expected 'unix://<path>'"
+                )),
+                false => Ok(Address::Unix(PathBuf::from(path))),
+            }
+        } else {
+            Err(format_err!(
+                "invalid address '{s}', expected 'tcp://<host>:<port>' or 'unix://<path>'"
+            ))
+        }
+    }
+}
+
+// Listening sockets of a program by address, in the configured order. They
+// outlive its child processes, so connections queue up during restarts.
+#[derive(Default)]
+pub struct Sockets(Vec<(String, OwnedFd)>);
+
+impl Sockets {
+    // Listens on the addresses, taking the sockets already listening on some
+    // of them from the existing ones and closing the rest of those.
+    pub fn open(addrs: &[String], mut existing: BTreeMap<String, OwnedFd>) -> Result<Self> {
+        let mut sockets = Vec::new();
+        for addr in addrs {
+            let fd = match existing.remove(addr) {
+                Some(fd) => fd,
+                None => bind(&addr.parse()?).context(format!("failed to listen on {addr}"))?,
+            };
+            sockets.push((addr.clone(), fd));
+        }
+        Ok(Sockets(sockets))
+    }
+
+    // Duplicates the sockets, to be taken by Sockets::open.
+    pub fn dup(&self) -> Result<BTreeMap<String, OwnedFd>> {
+        self.0
+            .iter()
+            .map(|(addr, fd)| Ok((addr.clone(), fd.try_clone()?)))
+            .collect()
+    }
+
+    pub fn raw_fds(&self) -> BTreeMap<String, RawFd> {
+        self.0
+            .iter()
+            .map(|(addr, fd)| (addr.clone(), fd.as_raw_fd()))
+            .collect()
+    }
+
+    // Waits at most the timeout for a connection on any of the sockets,
+    // without accepting it.
+    pub fn wait_connection(&self, timeout_ms: i32) -> Result<bool> {
+        let mut fds: Vec<_> = self
+            .0
+            .iter()
+            .map(|(_, fd)| poll::PollFd::new(fd.as_raw_fd(), poll::PollFlags::POLLIN))
+            .collect();
+        match poll::poll(&mut fds, timeout_ms) {
+            Ok(n) => Ok(n > 0),
+            Err(Errno::EINTR) => Ok(false),
+            Err(e) => Err(e).context("failed to poll listening sockets"),
+        }
+    }
+
+    // Makes the command receive the sockets from descriptor 3 on, along with
+    // LISTEN_FDS and LISTEN_PID, to be called after every other pre_exec.
+    pub fn pass_to(&self, cmd: &mut Command) -> Result<()> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+        let mut exec = Exec::new(cmd, self)?;
+        unsafe {
+            cmd.pre_exec(move || exec.run());
+        }
+        Ok(())
+    }
+}
+
+fn bind(addr: &Address) -> Result<OwnedFd> {
+    match addr {
+        Address::Tcp(addr) => Ok(OwnedFd::from(TcpListener::bind(addr.as_str())?)),
+        Address::Unix(path) => {
+            if path.exists() {
+                if UnixStream::connect(path).is_ok() {
+                    return Err(format_err!("socket is being used by another process"));
+                }
+                std::fs::remove_file(path).context("failed to remove old socket file")?;
+            }
+            Ok(OwnedFd::from(UnixListener::bind(path)?))
+        }
+    }
+}
+
+// Executes the command from pre_exec, as LISTEN_PID must be the pid of the
+// child which is only known after forking. Everything gets prepared before,
+// since the child of a multithreaded process must not allocate.
+struct Exec {
+    program: CString,
+    // keep the strings the pointers point to
+    _args: Vec<CString>,
+    argv: Vec<*const libc::c_char>,
+    _envs: Vec<CString>,
+    // LISTEN_PID entry with room for the digits, written in the child
+    pid_env: Vec<u8>,
+    envp: Vec<*const libc::c_char>,
+    fds: Vec<RawFd>,
+}
+
+// the pointers only point into the strings owned by Exec
+unsafe impl Send for Exec {}
+unsafe impl Sync for Exec {}
+
+const PID_PREFIX: &str = "LISTEN_PID=";
+const PID_DIGITS: usize = 20;
+
+impl Exec {
+    fn new(cmd: &Command, sockets: &Sockets) -> Result<Self> {
+        let cstring = |s: &OsStr| {
+            CString::new(s.as_bytes())
+                .context(format!("{} contains a NUL byte", s.to_string_lossy()))
+        };
+
+        let program = cstring(cmd.get_program())?;
+        let mut args = vec![program.clone()];
+        for arg in cmd.get_args() {
+            args.push(cstring(arg)?);
+        }
+
+        // the environment the command would get, with the protocol's variables
+        let mut vars: BTreeMap<_, _> = std::env::vars_os().collect();
+        for (key, value) in cmd.get_envs() {
+            match value {
+                Some(value) => vars.insert(key.to_os_string(), value.to_os_string()),
+                None => vars.remove(key),
+            };
+        }
+        for key in ["LISTEN_FDS", "LISTEN_PID", "LISTEN_FDNAMES"] {
+            vars.remove(OsStr::new(key));
+        }
+        let mut envs = Vec::new();
+        for (key, value) in vars {
+            let mut var = key;
+            var.push("=");
+            var.push(value);
+            envs.push(cstring(&var)?);
+        }
+        envs.push(CString::new(format!("LISTEN_FDS={}", sockets.0.len()))?);
+        let mut pid_env = PID_PREFIX.as_bytes().to_vec();
+        pid_env.resize(PID_PREFIX.len() + PID_DIGITS + 1, 0);
+
+        let mut argv: Vec<_> = args.iter().map(|a| a.as_ptr()).collect();
+        argv.push(std::ptr::null());
+        let mut envp: Vec<_> = envs.iter().map(|e| e.as_ptr()).collect();
+        envp.push(pid_env.as_ptr() as *const libc::c_char);
+        envp.push(std::ptr::null());
+
+        Ok(Exec {
+            program,
+            _args: args,
+            argv,
+            _envs: envs,
+            pid_env,
+            envp,
+            fds: sockets.0.iter().map(|(_, fd)| fd.as_raw_fd()).collect(),
+        })
+    }
+
+    // Runs in the child, only returning if executing failed.
+    fn run(&mut self) -> std::io::Result<()> {
+        let mut pid = unsafe { libc::getpid() } as u64;
+        let mut digits = [0u8; PID_DIGITS];
+        let mut len = 0;
+        loop {
+            digits[len] = b'0' + (pid % 10) as u8;
+            len += 1;
+            pid /= 10;
+            if pid == 0 {
+                break;
+            }
+        }
+        let env = &mut self.pid_env[PID_PREFIX.len()..];
+        for i in 0..len {
+            env[i] = digits[len - 1 - i];
+        }
+        env[len] = 0;
+
+        // move the sockets out of the way first, so none gets overwritten
+        let mut moved = [0 as RawFd; MAX_SOCKETS];
+        let moved = match moved.get_mut(..self.fds.len()) {
+            Some(moved) => moved,
+            None => return Err(std::io::Error::from_raw_os_error(libc::EMFILE)),
+        };
+        let n = self.fds.len() as RawFd;
+        for (fd, moved) in self.fds.iter().zip(moved.iter_mut()) {
+            *moved = unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, FIRST_FD + n) };
+            if *moved < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        for (i, fd) in moved.iter().enumerate() {
+            // the duplicate is not closed on exec
+            if unsafe { libc::dup2(*fd, FIRST_FD + i as RawFd) } < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        unsafe {
+            libc::execvpe(
+                self.program.as_ptr(),
+                self.argv.as_ptr(),
+                self.envp.as_ptr(),
+            );
+        }
+        Err(std::io::Error::last_os_error())
+    }
+}
@@ -1,24 +1,85 @@
+mod check;
+mod cli;
 mod client;
 mod config;
+mod hook;
+mod http;
+mod import;
+mod listen;
+mod output;
+mod peer;
 mod process;
+mod programs;
 mod rotate;
+mod schedule;
 mod server;
+mod state;
+mod systemd;
+mod upgrade;
 
 use anyhow::Result;
-use config::Action;
+use clap::CommandFactory;
+use cli::{Command, ConfigCommand, GenerateCommand};
+use config::Config;
 use std::{io::Write, str::FromStr};
 
 fn main() -> Result<()> {
+    let command = cli::args().command.as_ref().unwrap_or(&Command::Serve);
     init_logger();
 
-    let action = Action::from_args()?;
-
-    if matches!(action, Action::Serve) {
-        let rotater = rotate::Rotater::new()?;
-        let process = process::Process::new(rotater)?;
-        server::run(process)?;
-    } else {
-        client::request(action)?;
+    match command {
+        Command::Request(request) | Command::Ctl { request } => {
+            let (action, timeout) = request.action();
+            client::request(action, timeout)?;
+        }
+        Command::Check { json } => {
+            let path = Config::path();
+            let problems = check::check(&path);
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&problems)?);
+            } else {
+                for problem in &problems {
+                    println!("{problem}");
+                }
+                if problems.is_empty() {
+                    println!("{path}: ok");
+                }
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Config(ConfigCommand::Dump { format }) => {
+            let path = Config::path();
+            let format = format.unwrap_or(config::Format::of(&path));
+            print!("{}", Config::load(&path)?.dump(format)?);
+        }
+        Command::Import { from, file } => {
+            let (conf, warnings) = import::import(*from, file)?;
+            for warning in warnings {
+                eprintln!("warning: {warning}");
+            }
+            print!("{conf}");
+        }
+        Command::Generate(GenerateCommand::Systemd) => {
+            print!("{}", systemd::unit(&Config::path())?);
+        }
+        Command::Generate(GenerateCommand::Completions { shell }) => {
+            clap_complete::generate(
+                *shell,
+                &mut cli::Cli::command(),
+                "sup",
+                &mut std::io::stdout(),
+            );
+        }
+        Command::Version => print!("{}", cli::Cli::command().render_version()),
+        _ => {
+            upgrade::init();
+            check::ensure_valid(&Config::path())?;
+            let previous = state::load(&Config::get().sup.state_file())?;
+            let programs = programs::Programs::new(previous)?;
+            server::run(programs)?;
+        }
     }
 
     Ok(())
@@ -27,7 +88,7 @@ fn main() -> Result<()> {
 fn init_logger() {
     let level = std::env::var("RUST_LOG").unwrap_or(String::from("info"));
     let level = log::LevelFilter::from_str(&level)
-        .expect(&format!("invalid log level: RUST_LOG={}", level));
+        .unwrap_or_else(|_| panic!("invalid log level: RUST_LOG={}", level));
 
     env_logger::Builder::new()
         .format(|buf, record| {
@@ -0,0 +1,191 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+};
+
+use log::warn;
+
+// How many recent lines are kept in memory for each stream.
+const RECENT_LINES: usize = 1000;
+// How many chunks a slow subscriber may lag behind before losing output.
+const SUBSCRIBER_BUFFER: usize = 1024;
+// How much output without a newline is held back waiting for one, before
+// passing it on as a line anyway, like progress bars redrawn with '\r'.
+pub const MAX_LINE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Display for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+// Output of a stream as read from the process, or a whole line when kept
+// as recent output.
+pub struct Chunk {
+    pub stream: Stream,
+    pub data: Vec<u8>,
+}
+
+pub type Subscription = Receiver<Arc<Chunk>>;
+
+// Fans out the output of the supervised process to live subscribers, and keeps
+// the most recent lines of each stream, since the log file does not tell
+// stdout and stderr apart.
+#[derive(Default)]
+pub struct Output {
+    stdout: Mutex<Lines>,
+    stderr: Mutex<Lines>,
+    subscribers: Mutex<Vec<SyncSender<Arc<Chunk>>>>,
+}
+
+#[derive(Default)]
+struct Lines {
+    recent: VecDeque<Arc<Chunk>>,
+    // output read, but not ended with a newline yet
+    pending: Vec<u8>,
+}
+
+impl Output {
+    // Sends the output to live subscribers as soon as it is read, partial
+    // lines included, so prompts and progress bars show up. Returns the lines
+    // it completes, kept as recent output, to be logged.
+    pub fn publish(&self, stream: Stream, data: &[u8]) -> Vec<Vec<u8>> {
+        let chunk = Arc::new(Chunk {
+            stream,
+            data: data.to_vec(),
+        });
+        // pending output changes along with sending, see subscribe
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|s| match s.try_send(Arc::clone(&chunk)) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("log subscriber is too slow, dropping output");
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+
+        let mut lines = self.lines_of(stream).lock().unwrap();
+        lines.pending.extend_from_slice(data);
+        let mut completed = Vec::new();
+        while let Some(end) = lines.pending.iter().position(|&b| b == b'\n') {
+            completed.push(lines.pending.drain(..=end).collect());
+        }
+        if lines.pending.len() > MAX_LINE {
+            completed.push(std::mem::take(&mut lines.pending));
+        }
+        for line in &completed {
+            lines.remember(stream, line);
+        }
+        completed
+    }
+
+    // Takes the output not ended with a newline, kept as recent output, like
+    // once the stream ended.
+    pub fn take_pending(&self, stream: Stream) -> Vec<u8> {
+        let mut lines = self.lines_of(stream).lock().unwrap();
+        let pending = std::mem::take(&mut lines.pending);
+        if !pending.is_empty() {
+            lines.remember(stream, &pending);
+        }
+        pending
+    }
+
+    // Subscribes to live output, starting with output not ended with a
+    // newline yet, so subscribers see whole lines.
+    pub fn subscribe(&self) -> Subscription {
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for stream in [Stream::Stdout, Stream::Stderr] {
+            let data = self.lines_of(stream).lock().unwrap().pending.clone();
+            if !data.is_empty() {
+                // the channel is new, there is room
+                let _ = tx.try_send(Arc::new(Chunk { stream, data }));
+            }
+        }
+        subscribers.push(tx);
+        rx
+    }
+
+    // Returns at most the last n lines of the stream kept in memory.
+    pub fn recent(&self, stream: Stream, n: usize) -> Vec<Arc<Chunk>> {
+        let lines = self.lines_of(stream).lock().unwrap();
+        lines
+            .recent
+            .iter()
+            .skip(lines.recent.len().saturating_sub(n))
+            .cloned()
+            .collect()
+    }
+
+    fn lines_of(&self, stream: Stream) -> &Mutex<Lines> {
+        match stream {
+            Stream::Stdout => &self.stdout,
+            Stream::Stderr => &self.stderr,
+        }
+    }
+}
+
+impl Lines {
+    fn remember(&mut self, stream: Stream, line: &[u8]) {
+        if self.recent.len() >= RECENT_LINES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(Arc::new(Chunk {
+            stream,
+            data: line.to_vec(),
+        }));
+    }
+}
+
+// Removes ANSI escape sequences, like colors and cursor movements, and
+// carriage returns from the line.
+pub fn strip_ansi(line: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(line.len());
+    let mut bytes = line.iter().copied().peekable();
+
+    while let Some(b) = bytes.next() {
+        match b {
+            0x1b => match bytes.next() {
+                // CSI: parameters and intermediates up to a final byte in '@'..='~'
+                Some(b'[') => {
+                    for b in bytes.by_ref() {
+                        if (0x40..=0x7e).contains(&b) {
+                            break;
+                        }
+                    }
+                }
+                // OSC: up to BEL or ST (ESC \\)
+                Some(b']') => {
+                    while let Some(b) = bytes.next() {
+                        if b == 0x07 {
+                            break;
+                        }
+                        if b == 0x1b && bytes.peek() == Some(&b'\\') {
+                            bytes.next();
+                            break;
+                        }
+                    }
+                }
+                // other two bytes sequences
+                _ => {}
+            },
+            b'\r' => {}
+            _ => stripped.push(b),
+        }
+    }
+    stripped
+}
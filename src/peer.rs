@@ -0,0 +1,51 @@
+use std::os::unix::{io::AsRawFd, net::UnixStream};
+
+use anyhow::{Context, Result};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+
+// Credentials of the process on the other end of a connection.
+#[derive(Debug)]
+pub struct Peer {
+    pub pid: i32,
+    pub uid: u32,
+    // The primary group first, then the supplementary ones if known.
+    pub gids: Vec<u32>,
+}
+
+impl Peer {
+    pub fn of(c: &UnixStream) -> Result<Self> {
+        let cred = getsockopt(c.as_raw_fd(), PeerCredentials)
+            .context("failed to get credentials of peer")?;
+        let mut gids = vec![cred.gid()];
+        // SO_PEERCRED has only the primary group, the process may be gone
+        // already, so the others are best effort
+        for gid in supplementary_groups(cred.pid()) {
+            if !gids.contains(&gid) {
+                gids.push(gid);
+            }
+        }
+        Ok(Peer {
+            pid: cred.pid(),
+            uid: cred.uid(),
+            gids,
+        })
+    }
+}
+
+// The 'Groups:' line of /proc/[pid]/status in https://man7.org/linux/man-pages/man5/proc.5.html
+fn supplementary_groups(pid: i32) -> Vec<u32> {
+    let status = match std::fs::read_to_string(format!("/proc/{pid}/status")) {
+        Ok(status) => status,
+        Err(_) => return Vec::new(),
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| {
+            groups
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
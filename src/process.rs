@@ -1,174 +1,1005 @@
+use std::collections::BTreeMap;
 use std::fmt::Display;
-use std::ops::DerefMut;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::OwnedFd;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 use std::process;
 use std::process::Stdio;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::config;
-use crate::config::Config;
+use crate::hook;
+use crate::listen;
+use crate::output;
 use crate::rotate;
+use crate::schedule;
+use crate::state;
+use crate::upgrade;
 use anyhow::format_err;
 use anyhow::Context;
 use anyhow::Result;
+use chrono::Local;
+use log::error;
 use log::info;
+use log::warn;
+use nix::errno::Errno;
+use nix::fcntl;
+use nix::poll;
+use nix::pty;
 use nix::sys::signal;
 use nix::sys::signal::Signal;
 use nix::sys::stat;
+use nix::sys::termios;
+use nix::sys::wait;
 use nix::unistd;
 use nix::unistd::Pid;
 
+// How long reading output blocks at most, before checking for an upgrade.
+const COPY_POLL_MS: i32 = 200;
+// How often a program started on connection is checked for running.
+const WATCH_MS: i32 = 500;
+// How long the scheduler sleeps at most, before checking for an update.
+const SCHEDULE_POLL: Duration = Duration::from_secs(1);
+// Runs of a scheduled program kept for status.
+const HISTORY_RUNS: usize = 10;
+
+// Output and stdin of a child process handed over on upgrade.
+type HandedOver = (
+    Option<std::fs::File>,
+    Option<std::fs::File>,
+    Option<std::fs::File>,
+);
+
 pub struct Process {
     inner: Arc<ProcessInner>,
 }
 
 struct ProcessInner {
+    name: String,
     conf: config::Process,
     rotater: Arc<Mutex<rotate::Rotater>>,
+    events: Arc<hook::Dispatcher>,
+    output: Arc<output::Output>,
+    strip_ansi: bool,
+    stdin: Stdin,
     id_status: Arc<Mutex<ProcessIdStatus>>,
+    // fifos the current child process writes its output to
+    fifos: Mutex<Vec<(output::Stream, PathBuf)>>,
+    sockets: Arc<listen::Sockets>,
+}
+
+// Writing end of the stdin pipe or pty of the current child process.
+#[derive(Clone, Default)]
+pub struct Stdin(Arc<Mutex<Option<std::fs::File>>>);
+
+impl Write for Stdin {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(stdin) => stdin.write(buf),
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "process is not running",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(stdin) => stdin.flush(),
+            None => Ok(()),
+        }
+    }
 }
 
 struct ProcessIdStatus {
     pid: Option<u32>,
     desired_status: ProcessStatus,
+    // when the current child process got spawned
+    started_at: Option<SystemTime>,
+    // times the child process got restarted by the restart strategy
+    restarts: u32,
+    // latest runs of a scheduled program, the oldest first
+    runs: Vec<state::Run>,
+    // whether a scheduled run waits for the current one to exit
+    queued: bool,
+    // the current scheduler thread, older ones give up
+    scheduler: u64,
+    next_run: Option<SystemTime>,
 }
 
 impl Process {
-    pub fn new(rotater: rotate::Rotater) -> Result<Self> {
-        let conf = Config::get().program.process.clone();
+    // Sets up the program, re-adopting its child process from the state saved
+    // by an earlier sup if still running, or starting it if desired by that
+    // state, or by 'start' if there is none.
+    pub fn new(
+        name: &str,
+        conf: &config::Program,
+        previous: Option<state::ProgramState>,
+        start: bool,
+    ) -> Result<Self> {
+        let events = Arc::new(hook::Dispatcher::new(name, conf));
+        let rotater = rotate::Rotater::new(conf.log.clone(), Arc::clone(&events))?;
         let rotater = Arc::new(Mutex::new(rotater));
+        let strip_ansi = conf.log.strip_ansi.unwrap_or(conf.process.pty);
+
+        // owned right away, so they get closed if not taken
+        let handed_over = upgrade::take_program(name).map(|fds| {
+            let own = |fd: RawFd| unsafe { OwnedFd::from_raw_fd(fd) };
+            let file = |fd: Option<RawFd>| fd.map(|fd| std::fs::File::from(own(fd)));
+            let listen = fds.listen.into_iter().map(|(a, fd)| (a, own(fd))).collect();
+            (
+                (file(fds.stdout), file(fds.stderr), file(fds.stdin)),
+                listen,
+            )
+        });
+        let (handed_over, listen) = match handed_over {
+            Some((stdio, listen)) => (Some(stdio), listen),
+            None => (None, BTreeMap::new()),
+        };
+        let sockets = Arc::new(listen::Sockets::open(&conf.process.listen, listen)?);
 
         let id_status = Arc::new(Mutex::new(ProcessIdStatus {
             pid: None,
             desired_status: ProcessStatus::None,
+            started_at: None,
+            restarts: 0,
+            runs: previous
+                .as_ref()
+                .map(|p| p.runs.clone())
+                .unwrap_or_default(),
+            queued: false,
+            scheduler: 0,
+            next_run: None,
         }));
 
         let inner = Arc::new(ProcessInner {
-            conf,
+            name: String::from(name),
+            conf: conf.process.clone(),
             rotater,
+            events,
+            output: Arc::new(output::Output::default()),
+            strip_ansi,
+            stdin: Stdin::default(),
             id_status,
+            fifos: Mutex::new(Vec::new()),
+            sockets,
         });
 
         let p = Process { inner };
+        p.watch_connections();
+        p.watch_schedule();
 
-        if p.inner.conf.auto_start {
+        if let Some(previous) = &previous {
+            if p.adopt(previous, handed_over) {
+                return Ok(p);
+            }
+        }
+
+        if p.inner.conf.schedule.is_some() {
+            // launched by the scheduler, unless stopped
+            if previous.as_ref().is_none_or(|p| p.running) {
+                p.inner.id_status.lock().unwrap().desired_status = ProcessStatus::Running;
+            }
+            Self::save_state(&p.inner, &p.inner.id_status.lock().unwrap());
+            return Ok(p);
+        }
+
+        let start = match &previous {
+            Some(previous) => previous.running,
+            None => start,
+        };
+        if start {
             let pid = p.call_new_child()?;
             let mut is = p.inner.id_status.lock().unwrap();
             is.pid = Some(pid);
+            is.started_at = Some(SystemTime::now());
             is.desired_status = ProcessStatus::Running;
         }
+        Self::save_state(&p.inner, &p.inner.id_status.lock().unwrap());
 
         Ok(p)
     }
 
+    // Takes over the child process spawned by an earlier sup if it is still
+    // running, reconnecting to its output, through the descriptors handed
+    // over on upgrade if any. Returns whether it did.
+    fn adopt(&self, previous: &state::ProgramState, handed_over: Option<HandedOver>) -> bool {
+        let name = &self.inner.name;
+        let pid = match previous.pid {
+            Some(pid) => pid,
+            None => return false,
+        };
+        if previous.start_time.is_none() || start_time(pid) != previous.start_time {
+            info!("child process {pid} of program {name} is gone, not re-adopting it");
+            return false;
+        }
+
+        match handed_over {
+            Some((stdout, stderr, stdin)) => {
+                for (stream, f, fifo) in [
+                    (output::Stream::Stdout, stdout, &previous.stdout),
+                    (output::Stream::Stderr, stderr, &previous.stderr),
+                ] {
+                    if let Some(f) = f {
+                        Self::spawn_log_copier(&self.inner, stream, f, fifo.clone());
+                    }
+                }
+                *self.inner.stdin.0.lock().unwrap() = stdin;
+            }
+            None => {
+                for (stream, fifo) in [
+                    (output::Stream::Stdout, &previous.stdout),
+                    (output::Stream::Stderr, &previous.stderr),
+                ] {
+                    if let Some(fifo) = fifo {
+                        if let Err(e) = Self::reopen_log_fifo(&self.inner, stream, fifo) {
+                            warn!("failed to reconnect to {stream} of program {name}: {e:#}");
+                        }
+                    }
+                }
+                if self.inner.conf.pty {
+                    warn!("output of program {name} is not captured, its pty went away with the earlier sup");
+                }
+            }
+        }
+
+        let mut is = self.inner.id_status.lock().unwrap();
+        is.pid = Some(pid);
+        is.desired_status = if previous.running {
+            ProcessStatus::Running
+        } else {
+            ProcessStatus::None
+        };
+        is.started_at = previous
+            .started_at
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        is.restarts = previous.restarts;
+        Self::save_state(&self.inner, &is);
+
+        let inner = Arc::clone(&self.inner);
+        let started = is.started_at;
+        thread::spawn(move || Self::adopted_waiter(inner, pid, started));
+        info!("re-adopted child process {pid} of program {name}");
+        true
+    }
+
+    fn save_state(inner: &ProcessInner, is: &ProcessIdStatus) {
+        let fifos = inner.fifos.lock().unwrap();
+        let fifo = |stream| {
+            is.pid?;
+            fifos
+                .iter()
+                .find(|(s, _)| *s == stream)
+                .map(|(_, path)| path.clone())
+        };
+        state::record(
+            &inner.name,
+            state::ProgramState {
+                pid: is.pid,
+                start_time: is.pid.and_then(start_time),
+                running: matches!(is.desired_status, ProcessStatus::Running),
+                started_at: is
+                    .pid
+                    .and(is.started_at)
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs()),
+                restarts: is.restarts,
+                stdout: fifo(output::Stream::Stdout),
+                stderr: fifo(output::Stream::Stderr),
+                runs: is.runs.clone(),
+            },
+        );
+    }
+
     fn call_new_child(&self) -> Result<u32> {
         let inner = Arc::clone(&self.inner);
-        Self::new_child(inner)
+        Self::spawn_child(inner)
+    }
+
+    fn spawn_child(inner: Arc<ProcessInner>) -> Result<u32> {
+        let spawned =
+            Self::run_hooks(&inner, "preStart", &inner.conf.pre_start, None).and_then(|_| {
+                match inner.conf.schedule {
+                    Some(_) => Self::new_run(Arc::clone(&inner)),
+                    None => Self::new_child(Arc::clone(&inner)),
+                }
+            });
+
+        match spawned {
+            Ok(pid) => {
+                inner.events.emit(hook::Event::Start { pid });
+                if let Err(e) =
+                    Self::run_hooks(&inner, "postStart", &inner.conf.post_start, Some(pid))
+                {
+                    error!("{e:#}");
+                }
+                Ok(pid)
+            }
+            Err(e) => {
+                let reason = format!("{e:#}");
+                inner.events.emit(hook::Event::Fatal { reason });
+                Err(e)
+            }
+        }
     }
 
     fn new_child(inner: Arc<ProcessInner>) -> Result<u32> {
+        let child = Self::spawn_command(&inner)?;
+        let started = SystemTime::now();
+
+        thread::sleep(std::time::Duration::from_secs(inner.conf.start_seconds));
+        let pid = child.id();
+        let stat = ProcessStatus::get(pid)?;
+
+        match stat {
+            ProcessStatus::Running => {}
+            ProcessStatus::None => return Err(format_err!("process exited very quickly")),
+            // TODO: zombie
+            _ => return Err(format_err!("process in state: {}", stat)),
+        }
+
+        let inner = Arc::clone(&inner);
+        thread::spawn(move || Self::child_waiter(inner, child, started));
+
+        Ok(pid)
+    }
+
+    // Spawns a run of a scheduled program. Runs may well end within
+    // 'startSeconds', so they are not checked for running.
+    fn new_run(inner: Arc<ProcessInner>) -> Result<u32> {
+        let child = Self::spawn_command(&inner)?;
+        let started = SystemTime::now();
+        let pid = child.id();
+        thread::spawn(move || Self::child_waiter(inner, child, started));
+        Ok(pid)
+    }
+
+    // Spawns the child process with its output and stdin connected.
+    fn spawn_command(inner: &ProcessInner) -> Result<process::Child> {
+        let mut cmd = process::Command::new(&inner.conf.path);
+        cmd.args(&inner.conf.args).envs(&inner.conf.envs);
+        if !inner.conf.work_dir.is_empty() {
+            cmd.current_dir(&inner.conf.work_dir);
+        }
+        inner.fifos.lock().unwrap().clear();
+
+        let mut stdin = None;
+        if inner.conf.pty {
+            stdin = Some(Self::redirect_pty(inner, &mut cmd)?);
+        } else {
+            let log_stdout = Self::new_log_fifo(inner, output::Stream::Stdout)
+                .context("failed to open fifo for stdout redirecting")?;
+            let log_stderr = Self::new_log_fifo(inner, output::Stream::Stderr)
+                .context("failed to open fifo for stderr redirecting")?;
+            cmd.stdin(if inner.conf.stdin {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::from(log_stdout))
+            .stderr(Stdio::from(log_stderr));
+        }
+
+        inner
+            .sockets
+            .pass_to(&mut cmd)
+            .context("failed to pass listening sockets")?;
+        let mut child = cmd.spawn().context("failed to spawn child process")?;
+        // close our copies of the child's ends
+        drop(cmd);
+
+        info!("spawned child process of program {}", inner.name);
+        if let Some(child_stdin) = child.stdin.take() {
+            stdin = Some(std::fs::File::from(OwnedFd::from(child_stdin)));
+        }
+        *inner.stdin.0.lock().unwrap() = stdin;
+        Ok(child)
+    }
+
+    // Creates a fifo whose reading end copies the stream to the rotater and
+    // the output subscribers, and returns its writing end. The fifo is kept
+    // until the child closes it, so a restarted sup can reconnect.
+    fn new_log_fifo(inner: &ProcessInner, stream: output::Stream) -> Result<std::fs::File> {
         let tmp_path = tempfile::NamedTempFile::new().unwrap().into_temp_path();
-        let fifo_path = Arc::new(tmp_path.to_path_buf());
+        let fifo_path = tmp_path.to_path_buf();
         tmp_path.close()?;
 
         unistd::mkfifo(fifo_path.as_path(), stat::Mode::S_IRWXU)
             .context("failed to create log fifo")?;
 
-        let rotater = Arc::clone(&inner.rotater);
         let fifo_path_redirect = fifo_path.clone();
+        let copy = Self::log_copier(inner, stream);
 
         thread::spawn(move || {
-            let mut f =
+            let f =
                 std::fs::File::open(fifo_path_redirect.as_path()).expect("failed to open log fifo");
-            let mut thread_rotater = rotater.lock().unwrap();
-            std::io::copy(&mut f, thread_rotater.deref_mut())
-                .expect("failed to copy log from child process to rotataer");
+            copy(f);
+            let _ = std::fs::remove_file(fifo_path_redirect.as_path());
         });
 
-        let log_stdout = std::fs::OpenOptions::new()
+        // the child gets a reading end too, so it does not get SIGPIPE while
+        // no sup is reading, only blocks once the fifo is full
+        let f = std::fs::OpenOptions::new()
+            .read(true)
             .write(true)
             .open(fifo_path.as_path())
-            .context("failed to open fifo for stdout redirecting")?;
+            .context("failed to open log fifo")?;
+        inner.fifos.lock().unwrap().push((stream, fifo_path));
+        Ok(f)
+    }
 
-        let log_stderr = log_stdout
-            .try_clone()
-            .context("failed to oen fifo for stderr redirecting")?;
+    // Reconnects to a fifo created by an earlier sup for a child still running.
+    fn reopen_log_fifo(inner: &ProcessInner, stream: output::Stream, path: &Path) -> Result<()> {
+        // the child may be gone in between, then opening would block forever
+        let f = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(nix::libc::O_NONBLOCK)
+            .open(path)
+            .context(format!("failed to open log fifo {}", path.display()))?;
+        let flags = fcntl::OFlag::from_bits_truncate(
+            fcntl::fcntl(f.as_raw_fd(), fcntl::FcntlArg::F_GETFL)
+                .context("failed to get flags of log fifo")?,
+        );
+        fcntl::fcntl(
+            f.as_raw_fd(),
+            fcntl::FcntlArg::F_SETFL(flags - fcntl::OFlag::O_NONBLOCK),
+        )
+        .context("failed to set log fifo blocking")?;
 
-        // TODO: env variables & work dir
-        let child = process::Command::new(&inner.conf.path)
-            .args(&inner.conf.args)
-            .stdin(Stdio::null())
-            .stdout(Stdio::from(log_stdout))
-            .stderr(Stdio::from(log_stderr))
-            .spawn()
-            .context("failed to spawn child process")?;
+        Self::spawn_log_copier(inner, stream, f, Some(path.to_path_buf()));
+        Ok(())
+    }
 
-        info!("spawned child process");
-        std::fs::remove_file(fifo_path.as_path()).context("failed to remove log fifo")?;
+    // Copies the output in background, removing its fifo once the child
+    // closed it.
+    fn spawn_log_copier(
+        inner: &ProcessInner,
+        stream: output::Stream,
+        f: std::fs::File,
+        fifo: Option<PathBuf>,
+    ) {
+        let copy = Self::log_copier(inner, stream);
+        if let Some(fifo) = &fifo {
+            inner.fifos.lock().unwrap().push((stream, fifo.clone()));
+        }
+        thread::spawn(move || {
+            copy(f);
+            if let Some(fifo) = fifo {
+                let _ = std::fs::remove_file(fifo);
+            }
+        });
+    }
 
-        thread::sleep(std::time::Duration::from_secs(inner.conf.start_seconds));
-        let pid = child.id();
-        let stat = ProcessStatus::get(pid)?;
+    // Returns a function copying the stream to the output subscribers as it
+    // is read, and line by line to the rotater, until the other end got
+    // closed.
+    fn log_copier(inner: &ProcessInner, stream: output::Stream) -> impl FnOnce(std::fs::File) {
+        let name = inner.name.clone();
+        let rotater = Arc::clone(&inner.rotater);
+        let output = Arc::clone(&inner.output);
+        let strip_ansi = inner.strip_ansi;
 
-        match stat {
-            ProcessStatus::Running => {}
-            ProcessStatus::None => return Err(format_err!("process exited very quickly")),
-            // TODO: zombie
-            _ => return Err(format_err!("process in state: {}", stat)),
+        move |mut f| {
+            let write = |rotater: &mut rotate::Rotater, line: &[u8]| {
+                let log = if strip_ansi {
+                    output::strip_ansi(line)
+                } else {
+                    line.to_vec()
+                };
+                rotater
+                    .write_all(&log)
+                    .expect("failed to copy log from child process to rotataer");
+            };
+
+            // polls instead of blocking in read, to get to pause points
+            let copier = upgrade::Copier::register(&name, stream, f.as_raw_fd());
+            let mut buf = [0; 8192];
+            loop {
+                if copier.pausing() {
+                    // the upgraded sup copies the rest of the line
+                    let pending = output.take_pending(stream);
+                    write(&mut rotater.lock().unwrap(), &pending);
+                    copier.pause_point();
+                }
+
+                let mut fds = [poll::PollFd::new(f.as_raw_fd(), poll::PollFlags::POLLIN)];
+                match poll::poll(&mut fds, COPY_POLL_MS) {
+                    Ok(0) | Err(Errno::EINTR) => continue,
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("failed to poll {stream} of child process: {e}");
+                        break;
+                    }
+                }
+                let len = match f.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(len) => len,
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    // reading pty master fails with EIO once the child closed it
+                    Err(e) if e.raw_os_error() == Some(nix::libc::EIO) => break,
+                    Err(e) => {
+                        error!("failed to read {stream} of child process: {e}");
+                        break;
+                    }
+                };
+                // logging under the lock along with publishing, so what is
+                // logged matches what subscribers got, see logs
+                let mut rotater = rotater.lock().unwrap();
+                for line in output.publish(stream, &buf[..len]) {
+                    write(&mut rotater, &line);
+                }
+            }
+            let pending = output.take_pending(stream);
+            write(&mut rotater.lock().unwrap(), &pending);
         }
+    }
 
-        let inner = Arc::clone(&inner);
-        thread::spawn(move || Self::child_waiter(inner, child));
+    // Makes a pty the controlling terminal, stdin, stdout and stderr of the
+    // command, returning the master side for writing input.
+    fn redirect_pty(inner: &ProcessInner, cmd: &mut process::Command) -> Result<std::fs::File> {
+        let winsize = pty::Winsize {
+            ws_row: inner.conf.pty_rows,
+            ws_col: inner.conf.pty_cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        let pty = pty::openpty(&winsize, None).context("failed to open pty")?;
+        for fd in [pty.master, pty.slave] {
+            fcntl::fcntl(fd, fcntl::FcntlArg::F_SETFD(fcntl::FdFlag::FD_CLOEXEC))
+                .context("failed to set close-on-exec on pty")?;
+        }
+        let master = unsafe { std::fs::File::from_raw_fd(pty.master) };
+        let slave = unsafe { std::fs::File::from_raw_fd(pty.slave) };
 
-        Ok(pid)
+        // do not translate '\n' into '\r\n', which would end up in the log
+        let mut attrs =
+            termios::tcgetattr(slave.as_raw_fd()).context("failed to get pty attributes")?;
+        attrs.output_flags.remove(termios::OutputFlags::ONLCR);
+        termios::tcsetattr(slave.as_raw_fd(), termios::SetArg::TCSANOW, &attrs)
+            .context("failed to set pty attributes")?;
+
+        cmd.stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        unsafe {
+            cmd.pre_exec(|| {
+                unistd::setsid()?;
+                if nix::libc::ioctl(0, nix::libc::TIOCSCTTY as _, 0) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let reader = master.try_clone().context("failed to clone pty master")?;
+        Self::spawn_log_copier(inner, output::Stream::Stdout, reader, None);
+
+        Ok(master)
+    }
+
+    // Runs the commands one by one, stopping at the first failure.
+    fn run_hooks(
+        inner: &ProcessInner,
+        stage: &str,
+        commands: &[String],
+        pid: Option<u32>,
+    ) -> Result<()> {
+        let mut envs: Vec<(String, String)> = inner
+            .conf
+            .envs
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        envs.push((String::from("SUP_PROGRAM"), inner.name.clone()));
+        envs.push((String::from("SUP_HOOK"), String::from(stage)));
+        if let Some(pid) = pid {
+            envs.push((String::from("SUP_PID"), pid.to_string()));
+        }
+        let timeout = std::time::Duration::from_secs(inner.conf.hook_timeout_seconds);
+
+        for command in commands {
+            info!("running {stage} command '{command}'");
+            hook::run(command, &inner.conf.work_dir, &envs, &[], timeout)
+                .with_context(|| format!("{stage} command '{command}' failed"))?;
+        }
+        Ok(())
     }
 
-    fn child_waiter(inner: Arc<ProcessInner>, mut child: process::Child) {
+    fn child_waiter(inner: Arc<ProcessInner>, mut child: process::Child, started: SystemTime) {
+        let pid = child.id();
         let es = child.wait().unwrap();
-        info!("child process exited with code {}", es.code().unwrap());
+        info!("child process of program {} exited with {es}", inner.name);
+        Self::handle_exit(inner, pid, es.code(), es.signal(), Some(started));
+    }
+
+    // Waits for a child process spawned by an earlier sup. After an upgrade
+    // it is still a child of this process, otherwise it can not be waited
+    // for but polled, and its exit status is unknown, which counts as a
+    // failure for the restart strategy.
+    fn adopted_waiter(inner: Arc<ProcessInner>, pid: u32, started: Option<SystemTime>) {
+        match wait::waitpid(Pid::from_raw(pid as i32), None) {
+            Ok(wait::WaitStatus::Exited(_, code)) => {
+                info!("child process of program {} exited with {code}", inner.name);
+                return Self::handle_exit(inner, pid, Some(code), None, started);
+            }
+            Ok(wait::WaitStatus::Signaled(_, sig, _)) => {
+                info!("child process of program {} killed by {sig}", inner.name);
+                return Self::handle_exit(inner, pid, None, Some(sig as i32), started);
+            }
+            _ => {}
+        }
+        while matches!(ProcessStatus::get(pid), Ok(ProcessStatus::Running)) {
+            thread::sleep(Duration::from_millis(500));
+        }
+        info!("re-adopted child process of program {} exited", inner.name);
+        Self::handle_exit(inner, pid, None, None, started);
+    }
+
+    fn handle_exit(
+        inner: Arc<ProcessInner>,
+        pid: u32,
+        code: Option<i32>,
+        signal: Option<i32>,
+        started: Option<SystemTime>,
+    ) {
+        inner.events.emit(hook::Event::Exit { pid, code, signal });
 
         let mut is = inner.id_status.lock().unwrap();
+        let scheduled = inner.conf.schedule.is_some();
+        if let (true, Some(started)) = (scheduled, started) {
+            Self::record_run(&mut is, started, code, signal);
+        }
+        if is.pid != Some(pid) {
+            // stopped on purpose, and maybe started again already
+            if scheduled {
+                Self::save_state(&inner, &is);
+            }
+            return;
+        }
         is.pid.take();
+        inner.stdin.0.lock().unwrap().take();
 
         if matches!(is.desired_status, ProcessStatus::None) {
+            Self::save_state(&inner, &is);
             return;
         }
 
-        match inner.conf.restart_strategy {
-            config::RestartStrategy::None => {}
-            config::RestartStrategy::Always => {
-                let inner = Arc::clone(&inner);
-                is.pid = Some(Self::new_child(inner).expect("failed to restart process"));
+        // scheduled programs wait for their next run instead of restarting
+        if scheduled {
+            if std::mem::take(&mut is.queued) {
+                info!("launching queued run of program {}", inner.name);
+                Self::launch(&inner, &mut is);
+            }
+            Self::save_state(&inner, &is);
+            return;
+        }
+
+        let restart = match inner.conf.restart_strategy {
+            config::RestartStrategy::None => false,
+            config::RestartStrategy::Always => true,
+            config::RestartStrategy::OnFailure => code != Some(0),
+        };
+        if !restart {
+            Self::save_state(&inner, &is);
+            return;
+        }
+
+        match Self::spawn_child(Arc::clone(&inner)) {
+            Ok(pid) => {
+                is.pid = Some(pid);
+                is.started_at = Some(SystemTime::now());
+                is.restarts += 1;
+            }
+            Err(e) => {
+                error!("failed to restart program {}: {e:#}", inner.name);
+                is.desired_status = ProcessStatus::None;
+            }
+        }
+        Self::save_state(&inner, &is);
+    }
+
+    fn record_run(
+        is: &mut ProcessIdStatus,
+        started: SystemTime,
+        code: Option<i32>,
+        signal: Option<i32>,
+    ) {
+        is.runs.push(state::Run {
+            started_at: started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            duration_ms: started.elapsed().unwrap_or_default().as_millis() as u64,
+            code,
+            signal,
+        });
+        if is.runs.len() > HISTORY_RUNS {
+            is.runs.remove(0);
+        }
+    }
+
+    // Launches the program on its schedule while it is started, until the
+    // program gets removed or updated.
+    fn watch_schedule(&self) {
+        let id = {
+            let mut is = self.inner.id_status.lock().unwrap();
+            is.scheduler += 1;
+            is.next_run = None;
+            is.scheduler
+        };
+        let schedule = match &self.inner.conf.schedule {
+            Some(schedule) => match schedule.parse::<schedule::Schedule>() {
+                Ok(schedule) => schedule,
+                Err(e) => {
+                    error!("{e:#}");
+                    return;
+                }
+            },
+            None => return,
+        };
+        let weak = Arc::downgrade(&self.inner);
+        thread::spawn(move || {
+            let mut next = schedule.next_after(Local::now());
+            while let Some(time) = next {
+                let inner = match weak.upgrade() {
+                    Some(inner) => inner,
+                    None => return,
+                };
+                let mut is = inner.id_status.lock().unwrap();
+                if is.scheduler != id {
+                    return;
+                }
+                is.next_run = Some(SystemTime::from(time));
+                let wait = (time - Local::now()).to_std().unwrap_or_default();
+                if !wait.is_zero() {
+                    drop(is);
+                    drop(inner);
+                    thread::sleep(wait.min(SCHEDULE_POLL));
+                    continue;
+                }
+
+                if matches!(is.desired_status, ProcessStatus::Running) {
+                    Self::scheduled_run(&inner, &mut is);
+                    Self::save_state(&inner, &is);
+                }
+                next = schedule.next_after(time.max(Local::now()));
             }
-            config::RestartStrategy::OnFailure => {
-                if !es.success() {
-                    let inner = Arc::clone(&inner);
-                    is.pid = Some(Self::new_child(inner).expect("failed to restart process"));
+        });
+    }
+
+    fn scheduled_run(inner: &Arc<ProcessInner>, is: &mut ProcessIdStatus) {
+        let name = &inner.name;
+        if let Some(pid) = is.pid {
+            match inner.conf.schedule_overlap {
+                config::ScheduleOverlap::Skip => {
+                    info!("program {name} is still running, skipping its scheduled run");
+                    return;
+                }
+                config::ScheduleOverlap::Queue => {
+                    info!("program {name} is still running, queueing its scheduled run");
+                    is.queued = true;
+                    return;
                 }
+                config::ScheduleOverlap::KillPrevious => {
+                    info!("program {name} is still running, stopping it for its scheduled run");
+                    is.pid = None;
+                    if let Err(e) = Self::stop_child(inner, pid) {
+                        error!("failed to stop program {name}: {e:#}");
+                        return;
+                    }
+                }
+            }
+        }
+        info!("launching program {name} on schedule");
+        Self::launch(inner, is);
+    }
+
+    fn launch(inner: &Arc<ProcessInner>, is: &mut ProcessIdStatus) {
+        match Self::spawn_child(Arc::clone(inner)) {
+            Ok(pid) => {
+                is.pid = Some(pid);
+                is.started_at = Some(SystemTime::now());
             }
+            Err(e) => error!("failed to launch program {}: {e:#}", inner.name),
         }
     }
 
+    // Starts the program on a connection to its sockets while not running,
+    // if configured so, until the program gets removed or updated.
+    fn watch_connections(&self) {
+        if !self.inner.conf.start_on_connect {
+            return;
+        }
+        let weak = Arc::downgrade(&self.inner);
+        thread::spawn(move || {
+            while let Some(inner) = weak.upgrade() {
+                if inner.id_status.lock().unwrap().pid.is_some() {
+                    drop(inner);
+                    thread::sleep(Duration::from_millis(WATCH_MS as u64));
+                    continue;
+                }
+                match inner.sockets.wait_connection(WATCH_MS) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        error!("{e:#}");
+                        return;
+                    }
+                }
+
+                let mut is = inner.id_status.lock().unwrap();
+                if is.pid.is_some() {
+                    continue;
+                }
+                info!("connection to program {}, starting it", inner.name);
+                is.desired_status = ProcessStatus::Running;
+                match Self::spawn_child(Arc::clone(&inner)) {
+                    Ok(pid) => {
+                        is.pid = Some(pid);
+                        is.started_at = Some(SystemTime::now());
+                    }
+                    Err(e) => {
+                        error!("failed to start program {}: {e:#}", inner.name);
+                        is.desired_status = ProcessStatus::None;
+                    }
+                }
+                Self::save_state(&inner, &is);
+                drop(is);
+                // leave the connection to the child, or back off after failing
+                thread::sleep(Duration::from_millis(WATCH_MS as u64));
+            }
+        });
+    }
+
     pub fn start(&mut self) -> Result<()> {
         let mut is = self.inner.id_status.lock().unwrap();
         is.desired_status = ProcessStatus::Running;
 
         if is.pid.is_none() {
             is.pid = Some(self.call_new_child()?);
+            is.started_at = Some(SystemTime::now());
         }
+        Self::save_state(&self.inner, &is);
         Ok(())
     }
 
     pub fn stop(&mut self) -> Result<()> {
         let mut is = self.inner.id_status.lock().unwrap();
         is.desired_status = ProcessStatus::None;
+        is.queued = false;
 
-        if is.pid.is_some() {
-            let pid = is.pid.take().unwrap();
-            signal::kill(Pid::from_raw(pid as i32), Signal::SIGTERM)
-                .context("failed to send SIGTERM to child process")?;
-            Self::wait_for_none(pid)?;
+        if let Some(pid) = is.pid.take() {
+            Self::stop_child(&self.inner, pid)?;
+        }
+        Self::save_state(&self.inner, &is);
+        Ok(())
+    }
+
+    // Stops and starts the process, or with 'restartMode = "overlap"' starts
+    // the new one first and stops the old one once the new one is ready, that
+    // is still running after 'startSeconds'. If the new one fails to start,
+    // the old one keeps running.
+    pub fn restart(&mut self) -> Result<()> {
+        if self.inner.conf.restart_mode == config::RestartMode::StopStart {
+            self.stop()?;
+            return self.start();
+        }
+
+        let mut is = self.inner.id_status.lock().unwrap();
+        is.desired_status = ProcessStatus::Running;
+        let old = is.pid;
+        // the old process may exit while the new one starts, and its pid get
+        // reused by an unrelated process
+        let old_start_time = old.and_then(start_time);
+        is.pid = Some(self.call_new_child()?);
+        is.started_at = Some(SystemTime::now());
+        Self::save_state(&self.inner, &is);
+
+        // exiting, the old process is no longer the current one, so it does
+        // not get restarted
+        if let Some(old) = old {
+            if old_start_time.is_none() || start_time(old) != old_start_time {
+                info!(
+                    "old process {old} of program {} exited already",
+                    self.inner.name
+                );
+                return Ok(());
+            }
+            info!(
+                "new process of program {} is ready, stopping old process {old}",
+                self.inner.name
+            );
+            Self::stop_child(&self.inner, old)?;
+        }
+        Ok(())
+    }
+
+    // Runs the stop hooks around sending the stop signal and waiting for the
+    // child process to exit.
+    fn stop_child(inner: &ProcessInner, pid: u32) -> Result<()> {
+        let conf = &inner.conf;
+        if let Err(e) = Self::run_hooks(inner, "preStop", &conf.pre_stop, Some(pid)) {
+            error!("{e:#}");
+        }
+        let sig = conf.stop_signal.signal();
+        match signal::kill(Pid::from_raw(pid as i32), sig) {
+            Ok(()) => Self::wait_for_none(pid)?,
+            // exited already
+            Err(Errno::ESRCH) => {}
+            Err(e) => return Err(e).context(format!("failed to send {sig} to child process")),
+        }
+        if let Err(e) = Self::run_hooks(inner, "postStop", &conf.post_stop, Some(pid)) {
+            error!("{e:#}");
+        }
+        Ok(())
+    }
+
+    // Applies the new config of the program: re-opens the rotater if log
+    // settings changed, and restarts the process if its settings changed and
+    // it was running.
+    pub fn update(&mut self, old: &config::Program, new: &config::Program) -> Result<()> {
+        let name = self.inner.name.clone();
+
+        if old.events != new.events {
+            self.inner.events.reload(new);
+            info!("reloaded event hooks of program {name}");
+        }
+
+        if old.log != new.log {
+            let rotater = rotate::Rotater::new(new.log.clone(), Arc::clone(&self.inner.events))?;
+            *self.inner.rotater.lock().unwrap() = rotater;
+            info!("re-opened log {} of program {name}", new.log.path);
+        }
+
+        let strip_ansi = new.log.strip_ansi.unwrap_or(new.process.pty);
+        if old.process != new.process || strip_ansi != self.inner.strip_ansi {
+            let running = matches!(
+                self.inner.id_status.lock().unwrap().desired_status,
+                ProcessStatus::Running
+            );
+            if running {
+                self.stop()?;
+            }
+            let sockets = match old.process.listen == new.process.listen {
+                true => Arc::clone(&self.inner.sockets),
+                false => Arc::new(listen::Sockets::open(
+                    &new.process.listen,
+                    self.inner.sockets.dup()?,
+                )?),
+            };
+            self.inner = Arc::new(ProcessInner {
+                name,
+                conf: new.process.clone(),
+                rotater: Arc::clone(&self.inner.rotater),
+                events: Arc::clone(&self.inner.events),
+                output: Arc::clone(&self.inner.output),
+                strip_ansi,
+                stdin: self.inner.stdin.clone(),
+                id_status: Arc::clone(&self.inner.id_status),
+                fifos: Mutex::new(Vec::new()),
+                sockets,
+            });
+            self.watch_connections();
+            self.watch_schedule();
+            if running && new.process.schedule.is_some() {
+                // launched by the scheduler
+                let mut is = self.inner.id_status.lock().unwrap();
+                is.desired_status = ProcessStatus::Running;
+                Self::save_state(&self.inner, &is);
+            } else if running {
+                self.start()?;
+            }
+            info!("applied new process config of program {}", self.inner.name);
         }
         Ok(())
     }
@@ -176,17 +1007,28 @@ impl Process {
     pub fn reload(&mut self) -> Result<()> {
         let is = self.inner.id_status.lock().unwrap();
 
-        if is.pid.is_some() {
-            let pid = is.pid.unwrap();
+        if let Some(pid) = is.pid {
             signal::kill(Pid::from_raw(pid as i32), Signal::SIGHUP)
                 .context("failed to send SIGHUP to child process")?;
         }
         Ok(())
     }
 
+    // Sends the signal to the process, which must be running.
+    pub fn signal(&mut self, sig: Signal) -> Result<()> {
+        let is = self.inner.id_status.lock().unwrap();
+
+        match is.pid {
+            Some(pid) => signal::kill(Pid::from_raw(pid as i32), sig)
+                .context(format!("failed to send {sig} to child process")),
+            None => Err(format_err!("program is not running")),
+        }
+    }
+
     pub fn kill(&mut self) -> Result<()> {
         let mut is = self.inner.id_status.lock().unwrap();
         is.desired_status = ProcessStatus::None;
+        is.queued = false;
 
         if is.pid.is_some() {
             let pid = is.pid.take().unwrap();
@@ -194,6 +1036,7 @@ impl Process {
                 .context("failed to send SIGKILL to child process")?;
             Self::wait_for_none(pid)?;
         }
+        Self::save_state(&self.inner, &is);
         Ok(())
     }
 
@@ -208,17 +1051,107 @@ impl Process {
         Ok(())
     }
 
-    pub fn status(&self) -> Result<String> {
+    pub fn pid(&self) -> Option<u32> {
+        self.inner.id_status.lock().unwrap().pid
+    }
+
+    // When the current child process got spawned, if any.
+    pub fn started_at(&self) -> Option<SystemTime> {
         let is = self.inner.id_status.lock().unwrap();
-        let pid = is.pid;
+        is.pid.and(is.started_at)
+    }
 
-        if pid.is_none() {
-            Ok(String::from("NotStarted"))
-        } else {
-            let status = ProcessStatus::get(pid.unwrap())?;
-            Ok(format!("{status}"))
+    // How long the current child process has been running, if any.
+    pub fn uptime(&self) -> Option<Duration> {
+        let is = self.inner.id_status.lock().unwrap();
+        is.pid
+            .and(is.started_at)
+            .map(|t| t.elapsed().unwrap_or_default())
+    }
+
+    pub fn restarts(&self) -> u32 {
+        self.inner.id_status.lock().unwrap().restarts
+    }
+
+    // Latest runs of a scheduled program, the oldest first.
+    pub fn runs(&self) -> Vec<state::Run> {
+        self.inner.id_status.lock().unwrap().runs.clone()
+    }
+
+    // When a scheduled program gets launched next, unless stopped.
+    pub fn next_run(&self) -> Option<SystemTime> {
+        let is = self.inner.id_status.lock().unwrap();
+        match is.desired_status {
+            ProcessStatus::Running => is.next_run,
+            _ => None,
+        }
+    }
+
+    pub fn output(&self) -> Arc<output::Output> {
+        Arc::clone(&self.inner.output)
+    }
+
+    // Returns the last n lines of the log, or of stderr kept in memory.
+    pub fn logs(&self, n: usize, stderr: bool) -> Result<Vec<Vec<u8>>> {
+        self.tail(&self.inner.rotater.lock().unwrap(), n, stderr)
+    }
+
+    // Like logs, along with live output from there on. Output gets logged
+    // under the rotater lock, so holding it in between misses and repeats
+    // nothing.
+    pub fn follow_logs(
+        &self,
+        n: usize,
+        stderr: bool,
+    ) -> Result<(Vec<Vec<u8>>, output::Subscription)> {
+        let rotater = self.inner.rotater.lock().unwrap();
+        let lines = self.tail(&rotater, n, stderr)?;
+        Ok((lines, self.inner.output.subscribe()))
+    }
+
+    fn tail(&self, rotater: &rotate::Rotater, n: usize, stderr: bool) -> Result<Vec<Vec<u8>>> {
+        if stderr {
+            let recent = self.inner.output.recent(output::Stream::Stderr, n);
+            return Ok(recent.iter().map(|l| l.data.clone()).collect());
+        }
+        rotater.last(n)
+    }
+
+    // Returns None if stdin of the process is not configured to be a pipe or pty.
+    pub fn stdin(&self) -> Option<Stdin> {
+        let conf = &self.inner.conf;
+        (conf.stdin || conf.pty).then(|| self.inner.stdin.clone())
+    }
+
+    // Descriptors to hand over on upgrade, besides the output copied.
+    pub fn fds(&self) -> upgrade::ProgramFds {
+        let stdin = self.inner.stdin.0.lock().unwrap();
+        upgrade::ProgramFds {
+            stdin: stdin.as_ref().map(|f| f.as_raw_fd()),
+            listen: self.inner.sockets.raw_fds(),
+            ..Default::default()
         }
     }
+
+    pub fn status(&self) -> Result<String> {
+        let is = self.inner.id_status.lock().unwrap();
+        match is.pid {
+            None => Ok(String::from("NotStarted")),
+            Some(pid) => {
+                let status = ProcessStatus::get(pid)?;
+                Ok(format!("{status}"))
+            }
+        }
+    }
+}
+
+// Start time of the process in clock ticks after boot, field 22 of
+// /proc/[pid]/stat.
+fn start_time(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // fields after the command name, which may contain spaces, start at 3
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19)?.parse().ok()
 }
 
 // /proc/[pid]/stat in https://man7.org/linux/man-pages/man5/proc.5.html
@@ -241,7 +1174,7 @@ impl ProcessStatus {
             }
         }
         let stat = stat.unwrap();
-        let stat = stat.split_whitespace().skip(2).next().unwrap();
+        let stat = stat.split_whitespace().nth(2).unwrap();
 
         match stat {
             "R" | "S" | "D" => Ok(ProcessStatus::Running),
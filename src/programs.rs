@@ -0,0 +1,361 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    config::{self, Config, ProgramResult},
+    hook, process, state,
+};
+use anyhow::{format_err, Context, Result};
+use log::{error, info, warn};
+
+pub type Selected = Vec<(String, Arc<Mutex<process::Process>>)>;
+
+// Supervised programs by name, or by '<name>:<instance>' for programs with
+// several instances. Each process has its own lock, so a slow action on one
+// program does not hold up the others.
+pub struct Programs {
+    procs: BTreeMap<String, Arc<Mutex<process::Process>>>,
+}
+
+impl Programs {
+    // Sets up every program configured, taking over child processes still
+    // running from the state saved by an earlier sup.
+    pub fn new(mut previous: BTreeMap<String, state::ProgramState>) -> Result<Self> {
+        let conf = Config::get();
+        let needed = needed(&conf, conf.programs.keys());
+        let mut procs = BTreeMap::new();
+        for (name, program) in &in_dependency_order(&conf)? {
+            let start = program.process.auto_start || needed.contains(config::program_name(name));
+            let proc = process::Process::new(name, program, previous.remove(name), start)
+                .with_context(|| format!("failed to set up program {name}"))?;
+            procs.insert(name.clone(), Arc::new(Mutex::new(proc)));
+        }
+        for (name, program) in previous {
+            if let Some(pid) = program.pid {
+                warn!("program {name} is no longer configured, leaving its process {pid} alone");
+            }
+            state::forget(&name);
+        }
+        Ok(Programs { procs })
+    }
+
+    pub fn len(&self) -> usize {
+        self.procs.len()
+    }
+
+    // Looks up the programs by name, or returns all programs if no name is
+    // given. A program with several instances stands for all of them, and
+    // 'all' for every program. A group, also written '<group>:*', stands for
+    // its programs.
+    pub fn select(&self, names: &[String]) -> Result<Selected> {
+        if names.is_empty() {
+            return Ok(self
+                .procs
+                .iter()
+                .map(|(name, proc)| (name.clone(), Arc::clone(proc)))
+                .collect());
+        }
+        let conf = Config::get();
+        let mut selected: Selected = Vec::new();
+        for name in names {
+            let instances = self.matching(&conf, name);
+            if instances.is_empty() {
+                return Err(format_err!("unknown program or group '{name}'"));
+            }
+            for (name, proc) in instances {
+                if !selected.iter().any(|(n, _)| n == name) {
+                    selected.push((name.clone(), Arc::clone(proc)));
+                }
+            }
+        }
+        Ok(selected)
+    }
+
+    // Selects the programs along with the programs they depend on, each after
+    // the programs it depends on.
+    pub fn start_order(&self, names: &[String]) -> Result<Selected> {
+        let conf = Config::get();
+        let selected = self.select(names)?;
+        let mut wanted: Vec<String> = selected.iter().map(|(name, _)| name.clone()).collect();
+        for (name, _) in &selected {
+            wanted.extend(conf.dependencies(config::program_name(name)));
+        }
+        self.ordered(&conf, &wanted)
+    }
+
+    // Selects the programs along with the programs depending on them, each
+    // before the programs it depends on.
+    pub fn stop_order(&self, names: &[String]) -> Result<Selected> {
+        let conf = Config::get();
+        let selected = self.select(names)?;
+        let mut wanted: Vec<String> = selected.iter().map(|(name, _)| name.clone()).collect();
+        for (name, _) in &selected {
+            wanted.extend(conf.dependents(config::program_name(name)));
+        }
+        let mut ordered = self.ordered(&conf, &wanted)?;
+        ordered.reverse();
+        Ok(ordered)
+    }
+
+    // Selects the programs, each after the programs it depends on.
+    pub fn select_ordered(&self, names: &[String]) -> Result<Selected> {
+        let selected = self.select(names)?;
+        let wanted: Vec<String> = selected.into_iter().map(|(name, _)| name).collect();
+        self.ordered(&Config::get(), &wanted)
+    }
+
+    // The wanted programs in dependency order, a program name standing for
+    // all of its instances.
+    fn ordered(&self, conf: &Config, wanted: &[String]) -> Result<Selected> {
+        let mut ordered = Vec::new();
+        for program in conf.dependency_order()? {
+            for (name, proc) in &self.procs {
+                if config::program_name(name) == program
+                    && wanted.iter().any(|w| w == name || *w == program)
+                {
+                    ordered.push((name.clone(), Arc::clone(proc)));
+                }
+            }
+        }
+        Ok(ordered)
+    }
+
+    // Looks up the program by name, which may be omitted if there is only one.
+    pub fn select_one(&self, name: Option<&str>) -> Result<(String, Arc<Mutex<process::Process>>)> {
+        match name {
+            Some(name) => Ok((String::from(name), Arc::clone(self.get(name)?))),
+            None if self.procs.len() == 1 => {
+                let (name, proc) = self.procs.iter().next().unwrap();
+                Ok((name.clone(), Arc::clone(proc)))
+            }
+            None => {
+                let names: Vec<&str> = self.procs.keys().map(String::as_str).collect();
+                Err(format_err!(
+                    "more than one program configured, name one of: {}",
+                    names.join(", ")
+                ))
+            }
+        }
+    }
+
+    fn get(&self, name: &str) -> Result<&Arc<Mutex<process::Process>>> {
+        if let Some(proc) = self.procs.get(name) {
+            return Ok(proc);
+        }
+        let instances: Vec<&str> = self
+            .instances_of(name)
+            .into_iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        match instances.is_empty() {
+            true => Err(format_err!("unknown program '{name}'")),
+            false => Err(format_err!(
+                "program '{name}' has several instances, name one of: {}",
+                instances.join(", ")
+            )),
+        }
+    }
+
+    fn matching(&self, conf: &Config, name: &str) -> Vec<(&String, &Arc<Mutex<process::Process>>)> {
+        if let Some((name, proc)) = self.procs.get_key_value(name) {
+            return vec![(name, proc)];
+        }
+        let name = name.strip_suffix(":*").unwrap_or(name);
+        match conf.groups.get(name) {
+            Some(group) => group
+                .programs
+                .iter()
+                .flat_map(|program| self.matching(conf, program))
+                .collect(),
+            None if name == config::ALL => self.procs.iter().collect(),
+            None => self.instances_of(name),
+        }
+    }
+
+    fn instances_of(&self, program: &str) -> Vec<(&String, &Arc<Mutex<process::Process>>)> {
+        let prefix = format!("{program}:");
+        self.procs
+            .iter()
+            .filter(|(name, _)| name.starts_with(&prefix))
+            .collect()
+    }
+
+    // Applies the current config: stops programs no longer defined, updates
+    // the remaining ones and sets up the newly defined ones.
+    pub fn update(&mut self, old: &Config) -> Result<()> {
+        let conf = Config::get();
+        let new = in_dependency_order(&conf)?;
+        let added = conf
+            .programs
+            .keys()
+            .filter(|name| !old.programs.contains_key(*name));
+        let needed = needed(&conf, added);
+        let mut removed: Vec<String> = in_dependency_order(old)?
+            .into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| self.procs.contains_key(name) && !new.iter().any(|(n, _)| n == name))
+            .collect();
+        removed.reverse();
+        let old = old.instances();
+        for name in removed {
+            let proc = self.procs.remove(&name).unwrap();
+            proc.lock()
+                .unwrap()
+                .stop()
+                .with_context(|| format!("failed to stop removed program {name}"))?;
+            state::forget(&name);
+            info!("removed program {name}");
+        }
+
+        for (name, program) in &new {
+            let needed = needed.contains(config::program_name(name));
+            match (self.procs.get(name), old.get(name)) {
+                (Some(proc), Some(old)) => {
+                    let mut proc = proc.lock().unwrap();
+                    proc.update(old, program)
+                        .with_context(|| format!("failed to update program {name}"))?;
+                    if needed && proc.pid().is_none() {
+                        proc.start()
+                            .with_context(|| format!("failed to start program {name}"))?;
+                    }
+                }
+                _ => {
+                    let start = program.process.auto_start || needed;
+                    let proc = process::Process::new(name, program, None, start)
+                        .with_context(|| format!("failed to set up program {name}"))?;
+                    self.procs.insert(name.clone(), Arc::new(Mutex::new(proc)));
+                    info!("added program {name}");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The programs depended on by those of the given programs with 'autoStart',
+// started along with them.
+fn needed<'a>(conf: &Config, programs: impl Iterator<Item = &'a String>) -> BTreeSet<String> {
+    programs
+        .filter(|name| conf.programs[*name].process.auto_start)
+        .flat_map(|name| conf.dependencies(name))
+        .collect()
+}
+
+// Every instance of every program, each after the programs it depends on.
+fn in_dependency_order(conf: &Config) -> Result<Vec<(String, config::Program)>> {
+    let mut instances = Vec::new();
+    for program in conf.dependency_order()? {
+        instances.extend(conf.programs[&program].instances(&program));
+    }
+    Ok(instances)
+}
+
+// Runs the function on the selected programs one by one, returning the
+// outcome for each. A program is skipped if a program it depends on failed.
+pub fn for_each(
+    programs: &Mutex<Programs>,
+    select: impl FnOnce(&Programs) -> Result<Selected>,
+    f: impl Fn(&mut process::Process) -> Result<()>,
+) -> Result<Vec<ProgramResult>> {
+    let selected = select(&programs.lock().unwrap())?;
+
+    let conf = Config::get();
+    let mut failed: Vec<String> = Vec::new();
+    let mut results = Vec::new();
+    for (name, proc) in selected {
+        let dependencies = conf.dependencies(config::program_name(&name));
+        let result = match failed
+            .iter()
+            .find(|f| dependencies.contains(config::program_name(f)))
+        {
+            Some(f) => Err(format_err!("skipped, it depends on {f} which failed")),
+            None => f(&mut proc.lock().unwrap()),
+        };
+        let error = result.err().map(|e| format!("{e:#}"));
+        if error.is_some() {
+            failed.push(name.clone());
+        }
+        results.push(ProgramResult {
+            program: name,
+            error,
+        });
+    }
+    Ok(results)
+}
+
+// Collects the errors into one, each prefixed with its program name if there
+// is more than one outcome.
+pub fn joined(results: Vec<ProgramResult>) -> Result<()> {
+    let prefixed = results.len() > 1;
+    let errors: Vec<String> = results
+        .into_iter()
+        .filter_map(|r| match (r.error, prefixed) {
+            (Some(e), true) => Some(format!("{}: {e}", r.program)),
+            (Some(e), false) => Some(e),
+            (None, _) => None,
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(format_err!("{}", errors.join("\n")))
+    }
+}
+
+// Restarts the running programs with 'restartWithDependencies' whenever a
+// program they depend on starts anew, in the background.
+pub fn restart_dependents(programs: Arc<Mutex<Programs>>) {
+    let events = hook::subscribe();
+    std::thread::spawn(move || {
+        for event in events {
+            if event.name != "onStart" {
+                continue;
+            }
+            let conf = Config::get();
+            let program = config::program_name(&event.program);
+            let dependents: Vec<String> = conf
+                .programs
+                .iter()
+                .filter(|(_, p)| {
+                    p.process.restart_with_dependencies
+                        && p.process.depends_on.iter().any(|d| d == program)
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+            if dependents.is_empty() {
+                continue;
+            }
+            let selected = match programs.lock().unwrap().select(&dependents) {
+                Ok(selected) => selected,
+                Err(e) => {
+                    error!(
+                        "failed to restart dependents of program {}: {e:#}",
+                        event.program
+                    );
+                    continue;
+                }
+            };
+            // restarting waits for the process to be ready, events must not
+            // pile up meanwhile
+            for (name, proc) in selected {
+                let dependency = event.program.clone();
+                let started = event.time;
+                std::thread::spawn(move || {
+                    let mut proc = proc.lock().unwrap();
+                    // not running, or started after the dependency did, e.g.
+                    // along with it or after an earlier instance of it
+                    match proc.started_at() {
+                        Some(t) if t < started => {}
+                        _ => return,
+                    }
+                    info!("program {dependency} started, restarting program {name}");
+                    if let Err(e) = proc.restart() {
+                        error!("failed to restart program {name}: {e:#}");
+                    }
+                });
+            }
+        }
+    });
+}
@@ -1,28 +1,31 @@
-use crate::config::{self, Config};
+use crate::{config, hook};
 use anyhow::{Context, Result};
-use flate2::{write::GzEncoder, Compression};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use log::{error, info};
 use std::{
+    collections::VecDeque,
     ffi::OsStr,
     fs::File,
-    io::BufReader,
-    path::Path,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
     thread,
 };
 
+// UTC time of rotation in backup filenames.
+const TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S";
+
 pub struct Rotater {
     conf: config::Log,
     file: File,
     size: u64,
     write_mutex: Arc<Mutex<()>>,
     background_mutex: Arc<Mutex<()>>,
+    events: Arc<hook::Dispatcher>,
 }
 
 impl Rotater {
-    pub fn new() -> Result<Self> {
-        // TODO: 能否不 clone，同时减少 lifetime quailifier 的使用？
-        let conf = Config::get().program.log.clone();
+    pub fn new(conf: config::Log, events: Arc<hook::Dispatcher>) -> Result<Self> {
         let file = Self::new_file(&conf.path)?;
         let size = file.metadata().unwrap().len();
         let write_mutex = Arc::new(Mutex::new(()));
@@ -34,6 +37,7 @@ impl Rotater {
             size,
             write_mutex,
             background_mutex,
+            events,
         })
     }
 
@@ -48,36 +52,32 @@ impl Rotater {
             .context("failed to rename log file to rotated filename")?;
 
         self.file = Self::new_file(&self.conf.path)?;
+        self.size = 0;
         info!("rotated log {} to {rotated_path}", self.conf.path);
+        self.events.emit(hook::Event::Rotate {
+            path: self.conf.path.clone(),
+            rotated_path: rotated_path.clone(),
+        });
 
         let mu = Arc::clone(&self.background_mutex);
-        let compress = self.conf.compress;
-        let merge_compressed = self.conf.merge_compressed;
+        let conf = self.conf.clone();
 
         thread::spawn(move || {
-            Self::rotate_background(mu, rotated_path, compress, merge_compressed);
+            Self::rotate_background(mu, &conf, rotated_path);
         });
 
         Ok(())
     }
 
-    fn rotate_background<P: AsRef<Path>>(
-        mu: Arc<Mutex<()>>,
-        path: P,
-        compress: bool,
-        merge_compressed: bool,
-    ) {
+    fn rotate_background<P: AsRef<Path>>(mu: Arc<Mutex<()>>, conf: &config::Log, path: P) {
         let _x = mu.lock().unwrap();
-        if compress {
+        if conf.compress {
             if let Err(e) = Self::gzip(path) {
-                error!("failed to gzip rotated log: {e}");
-            }
-            if merge_compressed {
-                todo!();
+                error!("failed to gzip rotated log: {e:#}");
             }
         }
-        if let Err(e) = Self::clean_extra_backups() {
-            error!("failed to clean extra backups: {e}");
+        if let Err(e) = Self::clean_extra_backups(conf) {
+            error!("failed to clean extra backups: {e:#}");
         }
     }
 
@@ -96,6 +96,7 @@ impl Rotater {
         output
             .finish()
             .context("failed to finish gzipping rotated log")?;
+        std::fs::remove_file(path).context("failed to remove gzipped rotated log")?;
 
         info!(
             "compressed log {} to {path_output}",
@@ -105,8 +106,27 @@ impl Rotater {
         Ok(())
     }
 
-    fn clean_extra_backups() -> Result<()> {
-        todo!();
+    // Removes backups beyond the newest 'maxBackups', and those older than
+    // 'maxDays' by the time in their filename.
+    fn clean_extra_backups(conf: &config::Log) -> Result<()> {
+        let oldest = chrono::Utc::now().naive_utc() - chrono::Duration::days(conf.max_days.into());
+        let backups = Self::backup_files(&conf.path)?;
+        let mut seen: Vec<&str> = Vec::new();
+        for (timestamp, path) in &backups {
+            let time = chrono::NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT);
+            let expired = conf.max_days > 0 && time.is_ok_and(|t| t < oldest);
+            // a plain file and its gzipped copy count as one backup
+            if !seen.contains(&timestamp.as_str()) {
+                seen.push(timestamp);
+            }
+            let extra = conf.max_backups > 0 && seen.len() > conf.max_backups as usize;
+            if expired || extra {
+                std::fs::remove_file(path)
+                    .context(format!("failed to remove backup {}", path.display()))?;
+                info!("removed log backup {}", path.display());
+            }
+        }
+        Ok(())
     }
 
     fn new_file<P: AsRef<Path>>(path: P) -> Result<File> {
@@ -115,7 +135,6 @@ impl Rotater {
             std::fs::create_dir_all(dir).context("failed to create parent directory for log")?;
         }
         let file = std::fs::OpenOptions::new()
-            .write(true)
             .create(true)
             .truncate(false)
             .append(true)
@@ -124,12 +143,139 @@ impl Rotater {
         Ok(file)
     }
 
+    // Returns the last n lines of this log, see tail.
+    pub fn last(&self, n: usize) -> Result<Vec<Vec<u8>>> {
+        Self::tail(&self.conf, n)
+    }
+
+    // Returns the last n lines of the log, reaching across the most recent
+    // rotated and gzipped backups when the current log file is not long enough.
+    pub fn tail(conf: &config::Log, n: usize) -> Result<Vec<Vec<u8>>> {
+        let mut lines = VecDeque::new();
+        let mut files = vec![PathBuf::from(&conf.path)];
+        files.extend(Self::backups(&conf.path)?);
+
+        for file in files {
+            if lines.len() >= n {
+                break;
+            }
+            let want = n - lines.len();
+            let gzipped = file.extension().and_then(OsStr::to_str) == Some("gz");
+            let file_lines = if gzipped {
+                Self::tail_gzip(&file, want)
+            } else {
+                Self::tail_plain(&file, want)
+            }
+            .context(format!("failed to read log {}", file.display()))?;
+
+            for line in file_lines.into_iter().rev() {
+                lines.push_front(line);
+            }
+        }
+        Ok(lines.into())
+    }
+
+    fn tail_plain(path: &Path, n: usize) -> Result<Vec<Vec<u8>>> {
+        const BLOCK: u64 = 64 * 1024;
+
+        let mut f = match File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let size = f.metadata()?.len();
+
+        // read backwards block by block, until n lines before the last one got found
+        let mut start = size;
+        let mut buf = Vec::new();
+        while start > 0 && buf.iter().filter(|&&b| b == b'\n').count() <= n {
+            let len = BLOCK.min(start);
+            start -= len;
+            let mut block = vec![0; len as usize];
+            f.seek(SeekFrom::Start(start))?;
+            f.read_exact(&mut block)?;
+            block.extend(buf);
+            buf = block;
+        }
+
+        let mut lines: Vec<Vec<u8>> = buf
+            .split_inclusive(|&b| b == b'\n')
+            .map(|l| l.to_vec())
+            .collect();
+        if start > 0 && !lines.is_empty() {
+            // the first line is only part of a longer line
+            lines.remove(0);
+        }
+        Ok(lines.split_off(lines.len().saturating_sub(n)))
+    }
+
+    fn tail_gzip(path: &Path, n: usize) -> Result<Vec<Vec<u8>>> {
+        let mut reader = BufReader::new(GzDecoder::new(File::open(path)?));
+        let mut lines = VecDeque::with_capacity(n);
+        loop {
+            let mut line = Vec::new();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            if lines.len() == n {
+                lines.pop_front();
+            }
+            lines.push_back(line);
+        }
+        Ok(lines.into())
+    }
+
+    // Returns rotated backups of the log, newest first, preferring the plain
+    // file over its gzipped copy if both exist, like while gzipping.
+    fn backups(path: &str) -> Result<Vec<PathBuf>> {
+        let mut backups = Self::backup_files(path)?;
+        backups.dedup_by(|later, earlier| later.0 == earlier.0);
+        Ok(backups.into_iter().map(|(_, p)| p).collect())
+    }
+
+    // Returns rotated backups of the log by the time in their filename, newest
+    // first, a plain file before its gzipped copy.
+    fn backup_files(path: &str) -> Result<Vec<(String, PathBuf)>> {
+        let path = Path::new(path);
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            Some(_) => Path::new("."),
+            None => Path::new("/"),
+        };
+        let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
+        let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+        let prefix = format!("{stem}-");
+        let suffix = format!(".{ext}");
+
+        let mut backups: Vec<(String, PathBuf)> = Vec::new();
+        for entry in std::fs::read_dir(dir).context("failed to list log directory")? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(n) => n,
+                None => continue,
+            };
+            let timestamp = match name
+                .strip_prefix(&prefix)
+                .and_then(|n| n.strip_suffix(".gz").unwrap_or(n).strip_suffix(&suffix))
+            {
+                Some(t) if t.len() == 14 && t.bytes().all(|b| b.is_ascii_digit()) => t,
+                _ => continue,
+            };
+            backups.push((String::from(timestamp), entry.path()));
+        }
+
+        // newest first, plain file sorts before its gzipped copy
+        backups.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        Ok(backups)
+    }
+
     fn rotated_filename(path: &str) -> String {
         let now = chrono::Utc::now();
         let path = Path::new(path);
         let ext = path.extension().and_then(OsStr::to_str).unwrap_or_default();
         let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
-        format!("{stem}-{}.{ext}", now.format("%Y%m%d%H%M%S"))
+        format!("{stem}-{}.{ext}", now.format(TIMESTAMP_FORMAT))
     }
 }
 
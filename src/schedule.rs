@@ -0,0 +1,319 @@
+use std::str::FromStr;
+
+use anyhow::{format_err, Context, Result};
+use chrono::{
+    Datelike, Duration, Local, LocalResult, NaiveDate, NaiveDateTime, TimeZone, Timelike,
+};
+
+// How far ahead to look for the next time, so an impossible schedule like
+// '0 0 31 2 *' does not loop forever.
+const MAX_DAYS: i64 = 5 * 366;
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// A cron schedule: minute, hour, day of month, month and day of week, in
+// local time. Each field is '*', a value, a range like '1-5', a step like
+// '*/15' or '0-30/10', or a list of those like '1,15'. Months and days of
+// week may also be given by their first three letters, Sunday being 0 or 7.
+// A day matches if either day field does when both are restricted, as in
+// cron. '@hourly', '@daily', '@weekly', '@monthly' and '@yearly' stand for
+// the usual schedules.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    // bit N set for value N
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let expanded = match s.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format_err!(
+                "invalid schedule '{s}', expected 5 fields: minute, hour, day of month, month and day of week"
+            ));
+        };
+        let field = |name: &str, value: &str, min: u32, max: u32, names: &[&str]| {
+            parse_field(value, min, max, names)
+                .context(format!("invalid {name} '{value}' in schedule '{s}'"))
+        };
+
+        let mut weekdays = field("day of week", weekday, 0, 7, WEEKDAYS)?;
+        // 7 is Sunday too
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Schedule {
+            minutes: field("minute", minute, 0, 59, &[])?,
+            hours: field("hour", hour, 0, 23, &[])?,
+            days: field("day of month", day, 1, 31, &[])?,
+            months: field("month", month, 1, 12, MONTHS)?,
+            weekdays,
+            days_restricted: !day.starts_with('*'),
+            weekdays_restricted: !weekday.starts_with('*'),
+        })
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64> {
+    let value = |s: &str| -> Result<u32> {
+        let lower = s.to_ascii_lowercase();
+        let value = match names.iter().position(|n| *n == lower) {
+            // names count from the minimum, like 'jan' for 1
+            Some(i) => i as u32 + min,
+            None => s
+                .parse()
+                .map_err(|_| format_err!("'{s}' is not a number"))?,
+        };
+        if value < min || value > max {
+            return Err(format_err!("{value} is not within {min}-{max}"));
+        }
+        Ok(value)
+    };
+
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format_err!("invalid step '{step}'")),
+            },
+            None => (item, 1),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            // 'N/step' goes up to the maximum
+            None if item.contains('/') => (value(range)?, max),
+            None => {
+                let value = value(range)?;
+                (value, value)
+            }
+        };
+        if first > last {
+            return Err(format_err!("range {first}-{last} is backwards"));
+        }
+        for value in (first..=last).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl Schedule {
+    // The first time on the schedule after the given one, if any.
+    pub fn next_after(&self, after: chrono::DateTime<Local>) -> Option<chrono::DateTime<Local>> {
+        let after = after.naive_local();
+        let mut t = after.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let limit = after + Duration::days(MAX_DAYS);
+
+        while t < limit {
+            if !has(self.months, t.month()) {
+                let (year, month) = match t.month() {
+                    12 => (t.year() + 1, 1),
+                    month => (t.year(), month + 1),
+                };
+                t = midnight(NaiveDate::from_ymd_opt(year, month, 1)?);
+                continue;
+            }
+            if !self.matches_day(t.date()) {
+                t = midnight(t.date().succ_opt()?);
+                continue;
+            }
+            if !has(self.hours, t.hour()) {
+                t = t.with_minute(0)? + Duration::hours(1);
+                continue;
+            }
+            if !has(self.minutes, t.minute()) {
+                t += Duration::minutes(1);
+                continue;
+            }
+            match Local.from_local_datetime(&t) {
+                LocalResult::Single(time) => return Some(time),
+                // the earlier one when clocks go back
+                LocalResult::Ambiguous(time, _) => return Some(time),
+                // skipped when clocks go forward
+                LocalResult::None => t += Duration::minutes(1),
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day = has(self.days, date.day());
+        let weekday = has(self.weekdays, date.weekday().num_days_from_sunday());
+        match (self.days_restricted, self.weekdays_restricted) {
+            (true, true) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+fn has(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+fn midnight(date: NaiveDate) -> NaiveDateTime {
+    date.and_hms_opt(0, 0, 0).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Schedule {
+        s.parse().unwrap()
+    }
+
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> chrono::DateTime<Local> {
+        Local.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    fn values(bits: u64) -> Vec<u32> {
+        (0..64).filter(|&v| has(bits, v)).collect()
+    }
+
+    #[test]
+    fn steps_and_ranges() {
+        assert_eq!(values(parse("*/15 * * * *").minutes), [0, 15, 30, 45]);
+        assert_eq!(values(parse("0-30/10 * * * *").minutes), [0, 10, 20, 30]);
+        assert_eq!(
+            values(parse("5/10 * * * *").minutes),
+            [5, 15, 25, 35, 45, 55]
+        );
+        assert_eq!(values(parse("0 1-3,12 * * *").hours), [1, 2, 3, 12]);
+    }
+
+    #[test]
+    fn names_and_sunday_as_7() {
+        let s = parse("0 0 * JAN,mar-apr sun");
+        assert_eq!(values(s.months), [1, 3, 4]);
+        assert_eq!(values(s.weekdays), [0]);
+        assert_eq!(values(parse("0 0 * * 7").weekdays), [0]);
+        assert_eq!(values(parse("0 0 * * 5-7").weekdays), [0, 5, 6]);
+    }
+
+    #[test]
+    fn aliases() {
+        assert_eq!(parse("@yearly"), parse("0 0 1 1 *"));
+        assert_eq!(parse("@annually"), parse("0 0 1 1 *"));
+        assert_eq!(parse("@monthly"), parse("0 0 1 * *"));
+        assert_eq!(parse("@weekly"), parse("0 0 * * 0"));
+        assert_eq!(parse("@daily"), parse("0 0 * * *"));
+        assert_eq!(parse("@midnight"), parse("0 0 * * *"));
+        assert_eq!(parse("@hourly"), parse("0 * * * *"));
+    }
+
+    #[test]
+    fn invalid() {
+        for s in [
+            "*/0 * * * *",
+            "5- * * * *",
+            "30-10 * * * *",
+            "60 * * * *",
+            "0 24 * * *",
+            "0 0 0 * *",
+            "0 0 * 13 *",
+            "0 0 * * 8",
+            "0 0 * * foo",
+            "* * * *",
+            "* * * * * *",
+        ] {
+            assert!(s.parse::<Schedule>().is_err(), "'{s}' should be invalid");
+        }
+    }
+
+    #[test]
+    fn day_fields_or_when_both_restricted() {
+        // the 13th or any Friday; 2026-02-06 is a Friday
+        let s = parse("0 0 13 * fri");
+        assert_eq!(
+            s.next_after(local(2026, 2, 1, 0, 0)),
+            Some(local(2026, 2, 6, 0, 0))
+        );
+        assert_eq!(
+            s.next_after(local(2026, 2, 6, 0, 0)),
+            Some(local(2026, 2, 13, 0, 0))
+        );
+        // only Fridays when the day of month is '*'
+        let s = parse("0 0 * * fri");
+        assert_eq!(
+            s.next_after(local(2026, 2, 7, 0, 0)),
+            Some(local(2026, 2, 13, 0, 0))
+        );
+        // only the 13th when the day of week is '*'
+        let s = parse("0 0 13 * *");
+        assert_eq!(
+            s.next_after(local(2026, 2, 1, 0, 0)),
+            Some(local(2026, 2, 13, 0, 0))
+        );
+    }
+
+    #[test]
+    fn impossible_schedule() {
+        assert_eq!(
+            parse("0 0 31 2 *").next_after(local(2026, 1, 1, 0, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn next_after_rolls_over() {
+        let s = parse("*/15 * * * *");
+        assert_eq!(
+            s.next_after(local(2026, 1, 10, 10, 7)),
+            Some(local(2026, 1, 10, 10, 15))
+        );
+        assert_eq!(
+            s.next_after(local(2026, 1, 10, 10, 15)),
+            Some(local(2026, 1, 10, 10, 30))
+        );
+        // to the next hour and day
+        assert_eq!(
+            s.next_after(local(2026, 1, 10, 23, 50)),
+            Some(local(2026, 1, 11, 0, 0))
+        );
+        // to the next month
+        let s = parse("30 6 1 * *");
+        assert_eq!(
+            s.next_after(local(2026, 1, 31, 12, 0)),
+            Some(local(2026, 2, 1, 6, 30))
+        );
+        // to the next year
+        let s = parse("@yearly");
+        assert_eq!(
+            s.next_after(local(2026, 12, 31, 23, 59)),
+            Some(local(2027, 1, 1, 0, 0))
+        );
+        // skipping months without the day
+        let s = parse("0 0 31 * *");
+        assert_eq!(
+            s.next_after(local(2026, 1, 31, 0, 0)),
+            Some(local(2026, 3, 31, 0, 0))
+        );
+        // a leap day
+        let s = parse("0 12 29 2 *");
+        assert_eq!(
+            s.next_after(local(2026, 3, 1, 0, 0)),
+            Some(local(2028, 2, 29, 12, 0))
+        );
+    }
+}
@@ -1,66 +1,86 @@
 use std::{
     io::{BufRead, Read, Write},
-    os::unix::net::{UnixListener, UnixStream},
+    os::unix::{
+        fs::PermissionsExt,
+        io::{AsRawFd, FromRawFd},
+        net::{UnixListener, UnixStream},
+    },
     sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
+    check, cli,
     config::{self, Config},
-    process,
+    http, output, peer, process, programs, state, systemd, upgrade,
 };
 use anyhow::{format_err, Context, Result};
-use log::{error, info};
+use log::{error, info, warn};
+use nix::unistd;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
 
-pub fn run(process: process::Process) -> Result<()> {
-    let socket = &Config::get().sup.socket;
-    let process = Arc::new(Mutex::new(process));
-    run_stop_singal_handler(Arc::clone(&process))?;
-    run_server(socket, Arc::clone(&process))?;
+pub fn run(programs: programs::Programs) -> Result<()> {
+    let socket = cli::socket();
+    let programs = Arc::new(Mutex::new(programs));
+    run_stop_singal_handler(Arc::clone(&programs))?;
+    programs::restart_dependents(Arc::clone(&programs));
+    if let Some(conf) = &Config::get().sup.http {
+        http::run(conf, Arc::clone(&programs))?;
+    }
+    run_server(&socket, Arc::clone(&programs))?;
     Ok(())
 }
 
-fn run_stop_singal_handler(process: Arc<Mutex<process::Process>>) -> Result<()> {
-    let (tx, rx) = std::sync::mpsc::channel();
-
-    ctrlc::set_handler(move || tx.send(()).expect("cannot send signal on channel"))
-        .context("failed to set singal handler")?;
+fn run_stop_singal_handler(programs: Arc<Mutex<programs::Programs>>) -> Result<()> {
+    let mut signals =
+        Signals::new([SIGINT, SIGTERM, SIGHUP]).context("failed to set singal handler")?;
 
     std::thread::spawn(move || {
-        rx.recv().expect("failed to receive from singal channel");
-        handle_stop_singal(process);
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                info!("received SIGHUP, updating config...");
+                if let Err(e) = update(&mut programs.lock().unwrap()) {
+                    error!("failed to update config: {e:#}");
+                }
+                continue;
+            }
+            handle_stop_singal(programs);
+            break;
+        }
     });
 
     Ok(())
 }
 
-fn handle_stop_singal(process: Arc<Mutex<process::Process>>) {
-    let mut proc = process.lock().unwrap();
-    info!("received stop signal, stopping process...");
-    if let Err(e) = proc.stop() {
-        error!("failed to stop process {e}");
+fn handle_stop_singal(programs: Arc<Mutex<programs::Programs>>) {
+    info!("received stop signal, stopping programs...");
+    systemd::notify("STOPPING=1");
+    let stopped = programs::for_each(&programs, |p| p.stop_order(&[]), |proc| proc.stop());
+    if let Err(e) = stopped.and_then(programs::joined) {
+        error!("failed to stop programs: {e:#}");
         std::process::exit(1);
     }
+    state::clear();
     std::process::exit(0);
 }
 
-fn run_server(socket_path: &str, process: Arc<Mutex<process::Process>>) -> Result<()> {
-    let socket = std::path::Path::new(socket_path);
-
-    if socket.exists() {
-        if is_socket_being_used(socket_path) {
-            return Err(format_err!("sup socket is being used by another process"));
-        }
-        std::fs::remove_file(socket).context("failed to remove old sup socket file")?;
-    }
-
-    let server = UnixListener::bind(socket).context("failed to bind sup socket")?;
+fn run_server(socket_path: &str, programs: Arc<Mutex<programs::Programs>>) -> Result<()> {
+    let server = match upgrade::take_listener("socket") {
+        Some(fd) => unsafe { UnixListener::from_raw_fd(fd) },
+        None => bind(socket_path)?,
+    };
+    upgrade::register_listener("socket", server.as_raw_fd());
+    systemd::notify("READY=1");
 
     for client in server.incoming() {
         match client {
             Ok(c) => {
-                let proc = Arc::clone(&process);
+                let programs = Arc::clone(&programs);
                 std::thread::spawn(|| {
-                    if let Err(e) = handle_client(c, proc) {
+                    if let Err(e) = handle_client(c, programs) {
                         error!("failed to handle client: {e}");
                     }
                 });
@@ -74,51 +94,406 @@ fn run_server(socket_path: &str, process: Arc<Mutex<process::Process>>) -> Resul
     Ok(())
 }
 
-fn handle_client(mut c: UnixStream, process: Arc<Mutex<process::Process>>) -> Result<()> {
+fn bind(socket_path: &str) -> Result<UnixListener> {
+    let socket = std::path::Path::new(socket_path);
+
+    if socket.exists() {
+        if is_socket_being_used(socket_path) {
+            return Err(format_err!("sup socket is being used by another process"));
+        }
+        std::fs::remove_file(socket).context("failed to remove old sup socket file")?;
+    }
+
+    let server = UnixListener::bind(socket).context("failed to bind sup socket")?;
+    set_socket_permissions(socket_path, &Config::get().sup)?;
+    Ok(server)
+}
+
+fn set_socket_permissions(path: &str, sup: &config::Sup) -> Result<()> {
+    if let Some(mode) = sup.socket_mode()? {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .context(format!("failed to set mode of sup socket to {mode:o}"))?;
+    }
+    if let Some(owner) = &sup.socket_owner {
+        let (user, group) = match owner.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (owner.as_str(), None),
+        };
+        let uid = resolve_user(user)?;
+        let gid = group.map(resolve_group).transpose()?;
+        unistd::chown(path, Some(uid), gid)
+            .context(format!("failed to change owner of sup socket to {owner}"))?;
+    }
+    Ok(())
+}
+
+fn resolve_user(user: &str) -> Result<unistd::Uid> {
+    if let Ok(uid) = user.parse() {
+        return Ok(unistd::Uid::from_raw(uid));
+    }
+    match unistd::User::from_name(user).context(format!("failed to look up user {user}"))? {
+        Some(user) => Ok(user.uid),
+        None => Err(format_err!("unknown user '{user}'")),
+    }
+}
+
+fn resolve_group(group: &str) -> Result<unistd::Gid> {
+    if let Ok(gid) = group.parse() {
+        return Ok(unistd::Gid::from_raw(gid));
+    }
+    match unistd::Group::from_name(group).context(format!("failed to look up group {group}"))? {
+        Some(group) => Ok(group.gid),
+        None => Err(format_err!("unknown group '{group}'")),
+    }
+}
+
+fn handle_client(mut c: UnixStream, programs: Arc<Mutex<programs::Programs>>) -> Result<()> {
     use config::Action;
 
-    let mut buf = [0; 64];
+    let mut buf = [0; 1024];
     let len = c.read(&mut buf)?;
     let action = std::str::from_utf8(&buf[..len])?;
     info!("received action {action}");
 
-    let mut proc = process.lock().unwrap();
-
-    let rsp = match Action::from(action) {
-        Action::Start => proc.start(),
-        Action::Stop => proc.stop(),
-        Action::Reload => proc.reload(),
-        Action::Kill => proc.kill(),
-        Action::Exit => proc.stop(),
-        Action::Restart => {
-            if let Err(e) = proc.stop() {
-                Err(e)
-            } else {
-                proc.start()
+    let parsed: Action = match action.parse() {
+        Ok(a) => a,
+        Err(e) => {
+            c.write_all(format!("{e:#}").as_bytes())
+                .context("failed to write error message back")?;
+            return Ok(());
+        }
+    };
+
+    let peer = peer::Peer::of(&c)?;
+    if !Config::get()
+        .sup
+        .access
+        .allows(&parsed, peer.uid, &peer.gids)
+    {
+        warn!(
+            "denied action {action} from pid {} uid {} gids {:?}",
+            peer.pid, peer.uid, peer.gids
+        );
+        return write_error(
+            c,
+            format_err!("permission denied for action '{}'", parsed.name()),
+        );
+    }
+
+    let exit = matches!(parsed, Action::Exit | Action::ExitWait);
+    let rsp = match parsed {
+        Action::Logs(opts) => {
+            let selected = programs.lock().unwrap().select_one(opts.program.as_deref());
+            return match selected {
+                Ok((_, proc)) => {
+                    let proc = proc.lock().unwrap();
+                    let logs = if opts.follow {
+                        proc.follow_logs(opts.lines, opts.stderr)
+                            .map(|(lines, live)| (lines, Some(live)))
+                    } else {
+                        proc.logs(opts.lines, opts.stderr)
+                            .map(|lines| (lines, None))
+                    };
+                    drop(proc);
+                    serve_logs(c, opts, logs)
+                }
+                Err(e) => write_error(c, e),
+            };
+        }
+        Action::Attach(opts) => {
+            let selected = programs.lock().unwrap().select_one(opts.program.as_deref());
+            return match selected {
+                Ok((_, proc)) => {
+                    let (output, stdin) = {
+                        let proc = proc.lock().unwrap();
+                        (proc.output(), proc.stdin())
+                    };
+                    serve_attach(c, opts, output, stdin)
+                }
+                Err(e) => write_error(c, e),
+            };
+        }
+        Action::Upgrade => return serve_upgrade(c, &programs),
+        Action::Start(names) => {
+            programs::for_each(&programs, |p| p.start_order(&names), |proc| proc.start())
+                .map(Reply::Results)
+        }
+        Action::Stop(names) => {
+            programs::for_each(&programs, |p| p.stop_order(&names), |proc| proc.stop())
+                .map(Reply::Results)
+        }
+        Action::Reload(names) => {
+            programs::for_each(&programs, |p| p.select(&names), |proc| proc.reload())
+                .map(Reply::Results)
+        }
+        Action::Kill(names) => {
+            programs::for_each(&programs, |p| p.stop_order(&names), |proc| proc.kill())
+                .map(Reply::Results)
+        }
+        Action::Exit | Action::ExitWait => {
+            systemd::notify("STOPPING=1");
+            let stopped = programs::for_each(&programs, |p| p.stop_order(&[]), |proc| proc.stop())
+                .and_then(programs::joined);
+            if stopped.is_ok() {
+                state::clear();
             }
+            stopped.map(|_| Reply::Ok)
         }
-        Action::Status => match proc.status() {
-            Ok(s) => Err(format_err!("{s}")),
-            Err(e) => Err(format_err!("{e}")),
-        },
-        Action::Serve => Err(format_err!("do not support action {action}")),
+        Action::Restart(names) => programs::for_each(
+            &programs,
+            |p| p.select_ordered(&names),
+            |proc| proc.restart(),
+        )
+        .map(Reply::Results),
+        // reports what update would apply, so it fails as update would
+        Action::Reread => check::ensure_valid(&Config::path())
+            .and_then(|_| Config::reread())
+            .map(|new| Reply::Body(describe_changes(&Config::get(), &new) + "\n")),
+        Action::Update => update(&mut programs.lock().unwrap()).map(|_| Reply::Ok),
+        Action::Status(opts) => status(&programs, &opts).map(Reply::Body),
     };
 
     let rsp = match rsp {
-        Ok(()) => String::from("OK"),
-        Err(e) => format!("{e}"),
+        Ok(Reply::Ok) => String::from("OK"),
+        Ok(Reply::Body(body)) => format!("OK\n{body}"),
+        Ok(Reply::Results(results)) => {
+            format!("RESULTS\n{}", serde_json::to_string(&results).unwrap())
+        }
+        Err(e) => format!("{e:#}"),
     };
 
     c.write_all(rsp.as_bytes())
         .context("failed to write error message back")?;
 
-    if action == Action::Exit.to_string() {
+    if exit {
         std::process::exit(0);
     }
 
     Ok(())
 }
 
+// Successful replies to an action. Those with a body put it after an 'OK'
+// line, the outcome for each program acted on goes after a 'RESULTS' line.
+enum Reply {
+    Ok,
+    Body(String),
+    Results(Vec<config::ProgramResult>),
+}
+
+// Replies before re-executing sup, as the connection does not survive it.
+fn serve_upgrade(mut c: UnixStream, programs: &Mutex<programs::Programs>) -> Result<()> {
+    // the upgraded sup loads the config afresh, failing to start if invalid
+    if let Err(e) = check::ensure_valid(&Config::path()) {
+        return write_error(c, e);
+    }
+    // no other action may change the programs in between
+    let programs = programs.lock().unwrap();
+    let fds = programs
+        .select(&[])?
+        .into_iter()
+        .map(|(name, proc)| (name, proc.lock().unwrap().fds()))
+        .collect();
+    let upgrade = match upgrade::Upgrade::prepare(fds) {
+        Ok(upgrade) => upgrade,
+        Err(e) => return write_error(c, e),
+    };
+    if let Err(e) = c.write_all(b"OK") {
+        warn!("failed to reply to upgrade: {e}");
+    }
+    drop(c);
+
+    let e = upgrade.exec();
+    error!("failed to upgrade: {e:#}");
+    Ok(())
+}
+
+fn write_error(mut c: UnixStream, e: anyhow::Error) -> Result<()> {
+    c.write_all(format!("{e:#}").as_bytes())
+        .context("failed to write error message back")
+}
+
+// Lists status of the programs, one per line with its name if more than one
+// program is configured, or as a JSON array.
+fn status(programs: &Mutex<programs::Programs>, opts: &config::StatusOptions) -> Result<String> {
+    let (selected, prefixed) = {
+        let programs = programs.lock().unwrap();
+        (programs.select(&opts.programs)?, programs.len() > 1)
+    };
+
+    let mut statuses = Vec::new();
+    for (name, proc) in selected {
+        let proc = proc.lock().unwrap();
+        let status = proc
+            .status()
+            .with_context(|| format!("failed to get status of program {name}"))?;
+        statuses.push((name, status, proc.next_run(), proc.runs()));
+    }
+
+    if opts.json {
+        let statuses: Vec<_> = statuses
+            .into_iter()
+            .map(|(program, status, next_run, runs)| {
+                let mut status = serde_json::json!({ "program": program, "status": status });
+                // seconds since the epoch, like the start of runs
+                if let Some(next_run) = next_run.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+                    status["nextRun"] = serde_json::json!(next_run.as_secs());
+                }
+                if !runs.is_empty() {
+                    status["runs"] = serde_json::json!(runs);
+                }
+                status
+            })
+            .collect();
+        return Ok(serde_json::to_string_pretty(&statuses)? + "\n");
+    }
+    let mut out = String::new();
+    for (name, status, next_run, runs) in statuses {
+        if prefixed {
+            out += &format!("{name}: ");
+        }
+        out += &status;
+        if let Some(next_run) = next_run {
+            out += &format!(", next run at {}", format_time(next_run));
+        }
+        out += "\n";
+        // runs of scheduled programs below, the latest first
+        for run in runs.iter().rev() {
+            let started = UNIX_EPOCH + Duration::from_secs(run.started_at);
+            let end = match (run.code, run.signal) {
+                (Some(code), _) => format!("exited with {code}"),
+                (_, Some(signal)) => format!("killed by signal {signal}"),
+                _ => String::from("exited"),
+            };
+            out += &format!(
+                "  {} {end} after {:.1}s\n",
+                format_time(started),
+                run.duration_ms as f64 / 1000.0
+            );
+        }
+    }
+    Ok(out)
+}
+
+fn format_time(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Local>::from(time)
+        .format("%Y-%m-%d %H:%M:%S")
+        .to_string()
+}
+
+fn update(programs: &mut programs::Programs) -> Result<()> {
+    systemd::notify("RELOADING=1");
+    let updated = apply_update(programs);
+    systemd::notify("READY=1");
+    updated
+}
+
+fn apply_update(programs: &mut programs::Programs) -> Result<()> {
+    check::ensure_valid(&Config::path())?;
+    let old = Config::reload()?;
+    let new = Config::get();
+    info!("{}", describe_changes(&old, &new));
+    if old.sup.socket != new.sup.socket
+        || old.sup.socket_mode != new.sup.socket_mode
+        || old.sup.socket_owner != new.sup.socket_owner
+    {
+        warn!("changes of the sup socket take effect after restarting sup");
+    }
+    if old.sup.http.as_ref().map(|h| &h.listen) != new.sup.http.as_ref().map(|h| &h.listen) {
+        warn!("changes of 'sup.http.listen' take effect after restarting sup");
+    }
+    programs.update(&old)
+}
+
+fn describe_changes(old: &Config, new: &Config) -> String {
+    let changed = old.diff(new);
+    if changed.is_empty() {
+        String::from("config not changed")
+    } else {
+        format!("config changed: {}", changed.join(", "))
+    }
+}
+
+fn serve_logs(
+    mut c: UnixStream,
+    opts: config::LogsOptions,
+    logs: Result<(Vec<Vec<u8>>, Option<output::Subscription>)>,
+) -> Result<()> {
+    let (lines, live) = match logs {
+        Ok(logs) => logs,
+        Err(e) => {
+            c.write_all(format!("{e:#}").as_bytes())
+                .context("failed to write error message back")?;
+            return Ok(());
+        }
+    };
+
+    c.write_all(b"OK\n").context("failed to write logs back")?;
+    for line in lines {
+        c.write_all(&line).context("failed to write logs back")?;
+    }
+
+    if let Some(live) = live {
+        let only = opts.stderr.then_some(output::Stream::Stderr);
+        stream_output(c, live, only);
+    }
+    Ok(())
+}
+
+fn serve_attach(
+    mut c: UnixStream,
+    opts: config::AttachOptions,
+    output: Arc<output::Output>,
+    stdin: Option<process::Stdin>,
+) -> Result<()> {
+    let stdin = match (opts.stdin, stdin) {
+        (true, None) => {
+            c.write_all(b"stdin of program is not a pipe, set 'stdin = true' to enable it")
+                .context("failed to write error message back")?;
+            return Ok(());
+        }
+        (true, stdin) => stdin,
+        (false, _) => None,
+    };
+
+    let live = output.subscribe();
+    c.write_all(b"OK\n")
+        .context("failed to write output back")?;
+
+    if let Some(mut stdin) = stdin {
+        let mut input = c.try_clone().context("failed to clone client connection")?;
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                let len = match input.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => len,
+                };
+                if let Err(e) = stdin.write_all(&buf[..len]) {
+                    error!("failed to forward input to program: {e}");
+                }
+            }
+            info!("attached client detached");
+        });
+    }
+
+    stream_output(c, live, None);
+    Ok(())
+}
+
+// Writes live output to the client until it goes away, optionally only
+// output of the given stream.
+fn stream_output(mut c: UnixStream, live: output::Subscription, only: Option<output::Stream>) {
+    for chunk in live {
+        if only.is_some_and(|s| s != chunk.stream) {
+            continue;
+        }
+        if c.write_all(&chunk.data).is_err() {
+            // client went away
+            break;
+        }
+    }
+}
+
 fn is_socket_being_used(path: &str) -> bool {
     let f_info = "/proc/net/unix";
     let f = std::fs::File::open(f_info).expect("failed to open {f_info}");
@@ -0,0 +1,124 @@
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+#[derive(Default)]
+struct State {
+    // None until loaded, and after cleared, so nothing gets saved
+    path: Option<PathBuf>,
+    programs: BTreeMap<String, ProgramState>,
+}
+
+// What a restarted sup needs to re-adopt the child process of a program
+// instead of spawning a duplicate.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ProgramState {
+    pub pid: Option<u32>,
+    // Start time of the process from /proc/[pid]/stat, telling it apart from
+    // a later process given the same pid.
+    pub start_time: Option<u64>,
+    // Whether the program is desired to be running.
+    pub running: bool,
+    // Seconds since the epoch when the process got spawned.
+    pub started_at: Option<u64>,
+    pub restarts: u32,
+    // Fifos the process writes its stdout and stderr to.
+    pub stdout: Option<PathBuf>,
+    pub stderr: Option<PathBuf>,
+    // Latest runs of a scheduled program, the oldest first.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub runs: Vec<Run>,
+}
+
+// A finished run of a scheduled program.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Run {
+    // Seconds since the epoch when the process got spawned.
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+}
+
+// Reads the state saved by an earlier sup, if any, and saves the state to
+// the same file from now on.
+pub fn load(path: &Path) -> Result<BTreeMap<String, ProgramState>> {
+    let programs = match std::fs::read_to_string(path) {
+        Ok(src) => serde_json::from_str(&src)
+            .context(format!("failed to parse state file {}", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e).context(format!("failed to read state file {}", path.display())),
+    };
+
+    let mut state = STATE.lock().unwrap();
+    state.path = Some(path.to_path_buf());
+    state.programs = programs.clone();
+    Ok(programs)
+}
+
+pub fn record(name: &str, program: ProgramState) {
+    let mut state = STATE.lock().unwrap();
+    if state.programs.get(name) == Some(&program) {
+        return;
+    }
+    state.programs.insert(String::from(name), program);
+    save(&state);
+}
+
+pub fn forget(name: &str) {
+    let mut state = STATE.lock().unwrap();
+    if state.programs.remove(name).is_some() {
+        save(&state);
+    }
+}
+
+// Removes the state file once every program got stopped on purpose, so the
+// next sup starts afresh.
+pub fn clear() {
+    let mut state = STATE.lock().unwrap();
+    if let Some(path) = state.path.take() {
+        if let Err(e) = std::fs::remove_file(&path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("failed to remove state file {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+// Writes the state into a temporary file first, so a crash in between does
+// not leave a truncated one.
+fn save(state: &State) {
+    let path = match &state.path {
+        Some(path) => path,
+        None => return,
+    };
+    let saved = serde_json::to_vec_pretty(&state.programs)
+        .context("failed to serialize state")
+        .and_then(|json| {
+            let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+            let mut tmp = tempfile::NamedTempFile::new_in(dir.unwrap_or(Path::new(".")))
+                .context("failed to create temporary state file")?;
+            tmp.write_all(&json)
+                .context("failed to write temporary state file")?;
+            tmp.persist(path)
+                .context(format!("failed to replace state file {}", path.display()))?;
+            Ok(())
+        });
+    if let Err(e) = saved {
+        error!("failed to save state: {e:#}");
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    ffi::OsStr,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use log::warn;
+
+// Returns a unit running sup daemon with the config file, reloading and
+// stopping it through sup's own actions.
+pub fn unit(conf_path: &str) -> Result<String> {
+    let conf = Path::new(conf_path)
+        .canonicalize()
+        .context(format!("failed to resolve config file {conf_path}"))?;
+    let exe = std::env::current_exe().context("failed to resolve path of sup")?;
+    // relative paths in the config are resolved against the working directory
+    let dir = conf.parent().unwrap_or(Path::new("/"));
+    let sup = format!("{} -c {}", exe.display(), conf.display());
+
+    Ok(format!(
+        "\
+[Unit]
+Description=Sup process supervisor for {conf}
+Documentation=https://github.com/sequix/sup-rust
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+WorkingDirectory={dir}
+ExecStart={sup}
+ExecReload={sup} update
+ExecStop={sup} exit-wait
+Restart=on-failure
+RestartSec=1s
+# leave time for programs and their stop hooks to finish
+TimeoutStopSec=90s
+
+# Hardening, loosen it if the supervised programs need more.
+# The default socket lives in /tmp, so PrivateTmp stays off.
+NoNewPrivileges=yes
+ProtectSystem=full
+ProtectKernelTunables=yes
+ProtectKernelModules=yes
+ProtectKernelLogs=yes
+ProtectControlGroups=yes
+ProtectClock=yes
+ProtectHostname=yes
+RestrictSUIDSGID=yes
+RestrictRealtime=yes
+LockPersonality=yes
+
+[Install]
+WantedBy=multi-user.target
+",
+        conf = conf.display(),
+        dir = dir.display(),
+    ))
+}
+
+// Tells systemd about state changes of the daemon, when running as a unit of
+// Type=notify. Does nothing otherwise.
+pub fn notify(state: &str) {
+    let socket = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => socket,
+        None => return,
+    };
+    if let Err(e) = send(&socket, state) {
+        warn!("failed to notify systemd of {state}: {e:#}");
+    }
+}
+
+fn send(socket: &OsStr, state: &str) -> Result<()> {
+    let conn = UnixDatagram::unbound().context("failed to create notify socket")?;
+    // names starting with '@' are in the abstract namespace
+    let addr = match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    conn.send_to_addr(state.as_bytes(), &addr)
+        .context(format!("failed to send to {}", socket.to_string_lossy()))?;
+    Ok(())
+}
@@ -0,0 +1,279 @@
+use std::{
+    collections::BTreeMap,
+    os::unix::{io::RawFd, process::CommandExt},
+    path::PathBuf,
+    process::Command,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{output, systemd};
+use anyhow::{format_err, Context, Result};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use nix::fcntl;
+use serde::{Deserialize, Serialize};
+
+// Upgrading re-executes the sup binary in place. The process id stays the
+// same, so child processes remain children of sup and the state file tells
+// the new sup about them. Descriptors it needs are inherited, their numbers
+// passed in this variable.
+const ENV: &str = "SUP_UPGRADE";
+// How long to wait for log copying to get to a pause.
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Handover {
+    // listening sockets by name
+    listeners: BTreeMap<String, RawFd>,
+    programs: BTreeMap<String, ProgramFds>,
+}
+
+// Descriptors of a program: the reading ends of the output and the writing
+// end of stdin of its current child process, and its listening sockets.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ProgramFds {
+    pub stdout: Option<RawFd>,
+    pub stderr: Option<RawFd>,
+    pub stdin: Option<RawFd>,
+    // by address
+    pub listen: BTreeMap<String, RawFd>,
+}
+
+impl ProgramFds {
+    fn all(&self) -> impl Iterator<Item = RawFd> + '_ {
+        [self.stdout, self.stderr, self.stdin]
+            .into_iter()
+            .flatten()
+            .chain(self.listen.values().copied())
+    }
+}
+
+lazy_static! {
+    static ref HANDED_OVER: Mutex<Handover> = Mutex::new(handed_over());
+    static ref LISTENERS: Mutex<BTreeMap<String, RawFd>> = Mutex::new(BTreeMap::new());
+    static ref COPIERS: (Mutex<Copiers>, Condvar) = Default::default();
+}
+
+#[derive(Default)]
+struct Copiers {
+    pausing: bool,
+    running: usize,
+    paused: Vec<(String, output::Stream, RawFd)>,
+}
+
+// Reads the descriptors handed over by the sup which upgraded into this one,
+// before any child process could inherit the variable.
+pub fn init() {
+    lazy_static::initialize(&HANDED_OVER);
+}
+
+fn handed_over() -> Handover {
+    let json = match std::env::var(ENV) {
+        Ok(json) => json,
+        Err(_) => return Handover::default(),
+    };
+    std::env::remove_var(ENV);
+    match serde_json::from_str(&json) {
+        Ok(handover) => handover,
+        Err(e) => {
+            warn!("ignoring invalid {ENV}: {e}");
+            Handover::default()
+        }
+    }
+}
+
+// Takes the listening socket handed over under the name, if any.
+pub fn take_listener(name: &str) -> Option<RawFd> {
+    let fd = HANDED_OVER.lock().unwrap().listeners.remove(name)?;
+    set_inherited(fd, false).ok()?;
+    Some(fd)
+}
+
+// Takes the descriptors handed over for the program, if any.
+pub fn take_program(name: &str) -> Option<ProgramFds> {
+    let fds = HANDED_OVER.lock().unwrap().programs.remove(name)?;
+    for fd in fds.all() {
+        set_inherited(fd, false).ok()?;
+    }
+    Some(fds)
+}
+
+// Remembers a listening socket to hand over on upgrade.
+pub fn register_listener(name: &str, fd: RawFd) {
+    LISTENERS.lock().unwrap().insert(String::from(name), fd);
+}
+
+// A thread copying output of a program. It pauses between reads during an
+// upgrade, so no output is only in memory when sup gets re-executed.
+pub struct Copier {
+    program: String,
+    stream: output::Stream,
+    fd: RawFd,
+}
+
+impl Copier {
+    pub fn register(program: &str, stream: output::Stream, fd: RawFd) -> Self {
+        COPIERS.0.lock().unwrap().running += 1;
+        Copier {
+            program: String::from(program),
+            stream,
+            fd,
+        }
+    }
+
+    pub fn pausing(&self) -> bool {
+        COPIERS.0.lock().unwrap().pausing
+    }
+
+    // Blocks while an upgrade is going on.
+    pub fn pause_point(&self) {
+        let (copiers, cond) = &*COPIERS;
+        let mut c = copiers.lock().unwrap();
+        if !c.pausing {
+            return;
+        }
+        c.paused.push((self.program.clone(), self.stream, self.fd));
+        cond.notify_all();
+        while c.pausing {
+            c = cond.wait(c).unwrap();
+        }
+        c.paused.retain(|(_, _, fd)| *fd != self.fd);
+    }
+}
+
+impl Drop for Copier {
+    fn drop(&mut self) {
+        COPIERS.0.lock().unwrap().running -= 1;
+        COPIERS.1.notify_all();
+    }
+}
+
+// An upgrade ready to re-execute sup, with log copying paused.
+pub struct Upgrade {
+    exe: PathBuf,
+    handover: Handover,
+}
+
+impl Upgrade {
+    // Checks the binary to upgrade to and gets the descriptors ready to be
+    // inherited, taking those of programs besides their output by name.
+    pub fn prepare(programs: BTreeMap<String, ProgramFds>) -> Result<Self> {
+        let exe = exe()?;
+        let version = Command::new(&exe)
+            .arg("version")
+            .output()
+            .context(format!("failed to run {}", exe.display()))?;
+        if !version.status.success() {
+            return Err(format_err!(
+                "{} is not a working sup binary, 'version' {}",
+                exe.display(),
+                version.status
+            ));
+        }
+        info!(
+            "upgrading to {}",
+            String::from_utf8_lossy(&version.stdout).trim()
+        );
+
+        let mut handover = Handover {
+            listeners: LISTENERS.lock().unwrap().clone(),
+            programs,
+        };
+        for (name, stream, fd) in pause_copiers()? {
+            let fds = handover.programs.entry(name).or_default();
+            match stream {
+                output::Stream::Stdout => fds.stdout = Some(fd),
+                output::Stream::Stderr => fds.stderr = Some(fd),
+            }
+        }
+
+        let upgrade = Upgrade { exe, handover };
+        for fd in upgrade.fds() {
+            if let Err(e) = set_inherited(fd, true) {
+                upgrade.abort();
+                return Err(e);
+            }
+        }
+        Ok(upgrade)
+    }
+
+    // Only returns if re-executing failed, with everything resumed.
+    pub fn exec(self) -> anyhow::Error {
+        systemd::notify("RELOADING=1");
+        let handover = serde_json::to_string(&self.handover).unwrap();
+        let e = Command::new(&self.exe)
+            .args(std::env::args_os().skip(1))
+            .env(ENV, handover)
+            .exec();
+        self.abort();
+        systemd::notify("READY=1");
+        anyhow::Error::new(e).context(format!("failed to execute {}", self.exe.display()))
+    }
+
+    fn abort(&self) {
+        for fd in self.fds() {
+            let _ = set_inherited(fd, false);
+        }
+        resume_copiers();
+    }
+
+    fn fds(&self) -> Vec<RawFd> {
+        let programs = self.handover.programs.values().flat_map(ProgramFds::all);
+        self.handover
+            .listeners
+            .values()
+            .copied()
+            .chain(programs)
+            .collect()
+    }
+}
+
+// The binary sup got started from, which may have been replaced since.
+fn exe() -> Result<PathBuf> {
+    let exe = std::env::current_exe().context("failed to resolve path of sup")?;
+    // the link to a replaced binary gets this appended
+    let exe = match exe.to_str().and_then(|s| s.strip_suffix(" (deleted)")) {
+        Some(path) => PathBuf::from(path),
+        None => exe,
+    };
+    if !exe.is_file() {
+        return Err(format_err!("sup binary {} not found", exe.display()));
+    }
+    Ok(exe)
+}
+
+fn pause_copiers() -> Result<Vec<(String, output::Stream, RawFd)>> {
+    let (copiers, cond) = &*COPIERS;
+    let mut c = copiers.lock().unwrap();
+    if c.pausing {
+        return Err(format_err!("another upgrade is going on"));
+    }
+    c.pausing = true;
+    let deadline = Instant::now() + PAUSE_TIMEOUT;
+    while c.paused.len() < c.running {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            c.pausing = false;
+            cond.notify_all();
+            return Err(format_err!("timed out waiting for log copying to pause"));
+        }
+        c = cond.wait_timeout(c, left).unwrap().0;
+    }
+    Ok(c.paused.clone())
+}
+
+fn resume_copiers() {
+    COPIERS.0.lock().unwrap().pausing = false;
+    COPIERS.1.notify_all();
+}
+
+fn set_inherited(fd: RawFd, inherited: bool) -> Result<()> {
+    let flags = match inherited {
+        true => fcntl::FdFlag::empty(),
+        false => fcntl::FdFlag::FD_CLOEXEC,
+    };
+    fcntl::fcntl(fd, fcntl::FcntlArg::F_SETFD(flags))
+        .context(format!("failed to set close-on-exec of descriptor {fd}"))?;
+    Ok(())
+}